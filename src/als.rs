@@ -0,0 +1,293 @@
+//! Ambient light sensor support, through IIO
+//!
+//! Framework laptops (and plenty of others) expose their ALS as
+//! `/sys/bus/iio/devices/iio:deviceN/in_illuminance_raw`, which we read every so often to keep the
+//! backlight off when the room is bright enough to not need it, or to pick a brightness to suit the room.
+//!
+//! https://www.kernel.org/doc/html/latest/driver-api/iio/core.html
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Relative to the sysfs root
+const IIO_DEVICES_DIR: &str = "bus/iio/devices";
+
+pub struct AmbientLight {
+    /// The device's directory, e.x. `/sys/bus/iio/devices/iio:device0`
+    path: PathBuf,
+    /// lux = (raw + offset) * scale
+    scale: f32,
+    offset: f32,
+}
+
+impl AmbientLight {
+    /// Find the first IIO device with an illuminance channel under `sysfs_root`
+    pub fn new(sysfs_root: &Path) -> Result<Self> {
+        let devices_dir = sysfs_root.join(IIO_DEVICES_DIR);
+        let mut candidates = vec![];
+        for entry in fs::read_dir(&devices_dir).with_context(|| format!("failed to read {}", devices_dir.display()))? {
+            let path = entry?.path();
+            if path.join("in_illuminance_raw").exists() {
+                candidates.push(path);
+            }
+        }
+        candidates.sort();
+        debug!("ambient light sensor candidates: {candidates:?}");
+        let path = candidates.into_iter().next()
+            .ok_or_else(|| anyhow!("no ambient light sensor found in {}", devices_dir.display()))?;
+
+        // both of these are optional, and mean no scaling when they're missing
+        let scale = read_optional(&path.join("in_illuminance_scale"))?.unwrap_or(1.0);
+        let offset = read_optional(&path.join("in_illuminance_offset"))?.unwrap_or(0.0);
+        info!("using ambient light sensor {} (scale={scale}, offset={offset})", path.display());
+        Ok(AmbientLight { path, scale, offset })
+    }
+
+    /// The current illuminance, in lux
+    pub fn lux(&self) -> Result<f32> {
+        let raw_path = self.path.join("in_illuminance_raw");
+        let raw = read_f32(&raw_path)?;
+        Ok((raw + self.offset) * self.scale)
+    }
+}
+
+/// Whether it's too bright for the backlight, with some hysteresis so it doesn't flap around the threshold
+#[derive(Clone, Debug)]
+pub struct Threshold {
+    /// Above this many lux, it's bright
+    pub lux: f32,
+    /// It has to drop this far below `lux` to be dark again
+    pub hysteresis: f32,
+    bright: bool,
+}
+
+impl Threshold {
+    pub fn new(lux: f32, hysteresis: f32) -> Self {
+        Threshold { lux, hysteresis, bright: false }
+    }
+
+    /// Take a new reading, returns the new brightness if it flipped
+    pub fn update(&mut self, lux: f32) -> Option<bool> {
+        let bright = if self.bright {
+            lux >= self.lux - self.hysteresis
+        } else {
+            lux > self.lux
+        };
+        if bright == self.bright {
+            return None;
+        }
+        self.bright = bright;
+        Some(bright)
+    }
+}
+
+/// Piecewise linear mapping from lux to brightness, e.x. `0:100,50:60,300:20`
+#[derive(Clone, Debug)]
+pub struct Curve {
+    /// (lux, level), in order of lux
+    points: Vec<(f32, u8)>,
+}
+
+impl Curve {
+    /// The brightness for `lux`, holding the first/last level outside of the curve
+    pub fn level(&self, lux: f32) -> u8 {
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        if lux <= first.0 {
+            return first.1;
+        }
+        for pair in self.points.windows(2) {
+            let ((lux_a, level_a), (lux_b, level_b)) = (pair[0], pair[1]);
+            if lux <= lux_b {
+                let t = (lux - lux_a) / (lux_b - lux_a);
+                return (level_a as f32 + (level_b as f32 - level_a as f32) * t).round() as u8;
+            }
+        }
+        last.1
+    }
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut points: Vec<(f32, u8)> = vec![];
+        for point in s.split(',').filter(|p| !p.is_empty()) {
+            let (lux, level) = point.split_once(':')
+                .ok_or_else(|| format!("expected lux:level, got {point}"))?;
+            let lux = lux.parse::<f32>()
+                .ok().filter(|lux| lux.is_finite() && *lux >= 0.0)
+                .ok_or_else(|| format!("invalid lux: {lux}"))?;
+            let level = level.parse::<u8>()
+                .ok().filter(|level| *level <= 100)
+                .ok_or_else(|| format!("invalid level: {level}"))?;
+            if points.last().is_some_and(|&(last, _)| lux <= last) {
+                return Err(format!("lux has to go up along the curve, {lux} doesn't"));
+            }
+            points.push((lux, level));
+        }
+        if points.is_empty() {
+            return Err("the curve needs at least one lux:level point".to_string());
+        }
+        Ok(Curve { points })
+    }
+}
+
+/// Exponential moving average of the readings, so a passing shadow doesn't jerk the brightness around
+#[derive(Clone, Debug)]
+pub struct Smoothing {
+    /// Roughly how long a change takes to come through, zero to not smooth at all
+    pub window: Duration,
+    last: Option<(f32, Instant)>,
+}
+
+impl Smoothing {
+    pub fn new(window: Duration) -> Self {
+        Smoothing { window, last: None }
+    }
+
+    /// Take a new reading, returns the smoothed value
+    pub fn update(&mut self, lux: f32, now: Instant) -> f32 {
+        let smoothed = match self.last {
+            Some((last, then)) if !self.window.is_zero() => {
+                let elapsed = now.saturating_duration_since(then).as_secs_f32();
+                let alpha = 1.0 - (-elapsed / self.window.as_secs_f32()).exp();
+                last + (lux - last) * alpha
+            },
+            _ => lux,
+        };
+        self.last = Some((smoothed, now));
+        smoothed
+    }
+}
+
+fn read_f32(path: &Path) -> Result<f32> {
+    let contents = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    contents.trim().parse()
+        .with_context(|| format!("bad value in {}: {}", path.display(), contents.trim()))
+}
+
+/// [`read_f32`], but `None` if the file isn't there
+fn read_optional(path: &Path) -> Result<Option<f32>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    read_f32(path).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn finds_illuminance_channel() {
+        let root = TempDir::new();
+        // an accelerometer, not a light sensor
+        root.write("bus/iio/devices/iio:device0/in_accel_x_raw", "12");
+        root.write("bus/iio/devices/iio:device1/in_illuminance_raw", "100");
+        root.write("bus/iio/devices/iio:device2/in_illuminance_raw", "500");
+        let als = AmbientLight::new(root.path()).unwrap();
+        assert_eq!(als.path, root.path().join("bus/iio/devices/iio:device1"));
+        assert_eq!(als.lux().unwrap(), 100.0);
+    }
+
+    #[test]
+    fn applies_scale_and_offset() {
+        let root = TempDir::new();
+        root.write("bus/iio/devices/iio:device0/in_illuminance_raw", "100\n");
+        root.write("bus/iio/devices/iio:device0/in_illuminance_scale", "0.5\n");
+        root.write("bus/iio/devices/iio:device0/in_illuminance_offset", "-20\n");
+        let als = AmbientLight::new(root.path()).unwrap();
+        assert_eq!(als.lux().unwrap(), 40.0);
+
+        root.write("bus/iio/devices/iio:device0/in_illuminance_raw", "20\n");
+        assert_eq!(als.lux().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn no_sensor_is_an_error() {
+        let root = TempDir::new();
+        root.write("bus/iio/devices/iio:device0/in_accel_x_raw", "12");
+        assert!(AmbientLight::new(root.path()).is_err());
+        assert!(AmbientLight::new(&root.path().join("nope")).is_err());
+    }
+
+    #[test]
+    fn threshold_hysteresis() {
+        let mut threshold = Threshold::new(100.0, 10.0);
+        assert_eq!(threshold.update(50.0), None);
+        // has to go over, not just reach it
+        assert_eq!(threshold.update(100.0), None);
+        assert_eq!(threshold.update(101.0), Some(true));
+        assert_eq!(threshold.update(150.0), None);
+        // inside the band it stays bright
+        assert_eq!(threshold.update(95.0), None);
+        assert_eq!(threshold.update(90.0), None);
+        assert_eq!(threshold.update(89.0), Some(false));
+        // and inside the band it stays dark
+        assert_eq!(threshold.update(95.0), None);
+        assert_eq!(threshold.update(100.5), Some(true));
+    }
+
+    fn curve(s: &str) -> Curve {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn curve_interpolates() {
+        let curve = curve("0:100,50:60,300:20");
+        assert_eq!(curve.level(0.0), 100);
+        assert_eq!(curve.level(25.0), 80);
+        assert_eq!(curve.level(50.0), 60);
+        assert_eq!(curve.level(175.0), 40);
+        // rounded
+        assert_eq!(curve.level(1.0), 99);
+    }
+
+    #[test]
+    fn curve_holds_outside_its_points() {
+        let curve = curve("10:100,300:20");
+        assert_eq!(curve.level(0.0), 100);
+        assert_eq!(curve.level(10000.0), 20);
+        assert_eq!(self::curve("50:70").level(0.0), 70);
+        assert_eq!(self::curve("50:70").level(100.0), 70);
+    }
+
+    #[test]
+    fn curve_parsing() {
+        assert!("".parse::<Curve>().is_err());
+        assert!("0:100,50".parse::<Curve>().is_err());
+        assert!("0:101".parse::<Curve>().is_err());
+        assert!("-5:100".parse::<Curve>().is_err());
+        assert!("0:100,50:60,50:20".parse::<Curve>().is_err());
+        assert!("0:100,50:60,40:20".parse::<Curve>().is_err());
+        assert_eq!(curve("0:100,50:60,").points, vec![(0.0, 100), (50.0, 60)]);
+    }
+
+    #[test]
+    fn smoothing_disabled() {
+        let start = Instant::now();
+        let mut smoothing = Smoothing::new(Duration::ZERO);
+        assert_eq!(smoothing.update(100.0, start), 100.0);
+        assert_eq!(smoothing.update(0.0, start + Duration::from_millis(10)), 0.0);
+    }
+
+    #[test]
+    fn smoothing_follows_slowly() {
+        let start = Instant::now();
+        let mut smoothing = Smoothing::new(Duration::from_secs(5));
+        // the first reading is taken as is
+        assert_eq!(smoothing.update(100.0, start), 100.0);
+        // one window later, it's come 1 - 1/e of the way
+        let smoothed = smoothing.update(0.0, start + Duration::from_secs(5));
+        assert!((smoothed - 100.0 / std::f32::consts::E).abs() < 0.01, "{smoothed}");
+        // and with no time passing it doesn't move
+        assert_eq!(smoothing.update(1000.0, start + Duration::from_secs(5)), smoothed);
+        // but it gets there eventually
+        assert!(smoothing.update(0.0, start + Duration::from_secs(120)) < 0.01);
+    }
+}
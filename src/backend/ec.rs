@@ -0,0 +1,55 @@
+//! Framework embedded controller backend, via framework_lib
+use anyhow::{anyhow, Result};
+use framework_lib::chromium_ec::{CrosEc, CrosEcDriverType};
+
+use super::Backlight;
+
+pub struct EcBackend {
+    driver: CrosEcDriverType,
+    ec: Option<CrosEc>,
+}
+
+impl EcBackend {
+    pub fn new(driver: CrosEcDriverType) -> Self {
+        EcBackend {
+            driver,
+            ec: None,
+        }
+    }
+
+    fn handle(&mut self) -> Result<&CrosEc> {
+        self.acquire()?;
+        self.ec.as_ref().ok_or_else(|| anyhow!("Failed to access EC"))
+    }
+}
+
+impl Backlight for EcBackend {
+    fn name(&self) -> &str {
+        "ec"
+    }
+
+    fn acquire(&mut self) -> Result<()> {
+        if self.ec.is_none() {
+            match CrosEc::with(self.driver) {
+                Some(ec) => self.ec = Some(ec),
+                None => anyhow::bail!("Failed to access EC"),
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        self.ec = None;
+    }
+
+    fn set_level(&mut self, level: u8) -> Result<()> {
+        self.handle()?.set_keyboard_backlight(level);
+        Ok(())
+    }
+
+    fn level(&mut self) -> Result<Option<u8>> {
+        let level = self.handle()?.get_keyboard_backlight()
+            .map_err(|e| anyhow!("failed to read keyboard backlight from EC: {e:?}"))?;
+        Ok(Some(level))
+    }
+}
@@ -0,0 +1,125 @@
+//! Recording backend for `--dry-run`
+//!
+//! Doesn't touch any hardware, just logs every level it's asked to set along with a monotonic
+//! timestamp, and optionally writes them to a file as `<seconds> <sink> <level>` lines.
+use anyhow::{Context, Result};
+use log::info;
+
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::Backlight;
+
+/// A `--record` file shared by every sink
+#[derive(Clone)]
+pub struct Recording {
+    start: Instant,
+    file: Arc<Mutex<LineWriter<File>>>,
+}
+
+impl Recording {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(Recording {
+            start: Instant::now(),
+            file: Arc::new(Mutex::new(LineWriter::new(file))),
+        })
+    }
+}
+
+pub struct MockBackend {
+    name: String,
+    start: Instant,
+    level: u8,
+    /// How many levels we've been asked to set, and when the last one was
+    changes: usize,
+    last_change: Option<Duration>,
+    recording: Option<Recording>,
+}
+
+impl MockBackend {
+    pub fn new(name: String, starting_level: u8, recording: Option<Recording>) -> Self {
+        MockBackend {
+            name,
+            start: recording.as_ref().map(|r| r.start).unwrap_or_else(Instant::now),
+            level: starting_level,
+            changes: 0,
+            last_change: None,
+            recording,
+        }
+    }
+}
+
+impl Backlight for MockBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_level(&mut self, level: u8) -> Result<()> {
+        let elapsed = self.start.elapsed();
+        info!("dry run: {} level={level} at {elapsed:?}", self.name);
+        if let Some(ref recording) = self.recording {
+            let mut file = recording.file.lock().expect("recording lock poisoned");
+            writeln!(file, "{:.6} {} {level}", elapsed.as_secs_f64(), self.name)?;
+        }
+        self.changes += 1;
+        self.last_change = Some(elapsed);
+        self.level = level;
+        Ok(())
+    }
+
+    fn level(&mut self) -> Result<Option<u8>> {
+        Ok(Some(self.level))
+    }
+}
+
+impl Drop for MockBackend {
+    fn drop(&mut self) {
+        if let Some(last) = self.last_change {
+            info!("dry run: {} recorded {} changes over {last:?}", self.name, self.changes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn reads_back_what_was_set() {
+        let mut backend = MockBackend::new("mock".to_string(), 70, None);
+        assert_eq!(backend.level().unwrap(), Some(70));
+        backend.set_level(20).unwrap();
+        assert_eq!(backend.level().unwrap(), Some(20));
+        assert_eq!(backend.changes, 1);
+    }
+
+    #[test]
+    fn records_lines() {
+        let dir = TempDir::new();
+        let path = dir.path().join("levels.txt");
+        let recording = Recording::create(&path).unwrap();
+        let mut ec = MockBackend::new("ec".to_string(), 0, Some(recording.clone()));
+        let mut qmk = MockBackend::new("qmk".to_string(), 0, Some(recording));
+        ec.set_level(40).unwrap();
+        qmk.set_level(20).unwrap();
+        ec.set_level(100).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<(f64, &str, u8)> = contents.lines().map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields.len(), 3, "{line}");
+            (fields[0].parse().unwrap(), fields[1], fields[2].parse().unwrap())
+        }).collect();
+        let sinks: Vec<_> = lines.iter().map(|&(_, sink, level)| (sink, level)).collect();
+        assert_eq!(sinks, vec![("ec", 40), ("qmk", 20), ("ec", 100)]);
+        // one clock for every sink, so they line up
+        assert!(lines.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(contents.lines().all(|line| line.split(' ').next().unwrap().split_once('.').unwrap().1.len() == 6));
+    }
+}
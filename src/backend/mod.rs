@@ -0,0 +1,207 @@
+//! Backlight backends
+//!
+//! Everything that actually touches a keyboard backlight lives behind the [`Backlight`] trait,
+//! so the idle/fade logic doesn't need to know what it's talking to.
+use anyhow::Result;
+use log::{error, info};
+
+use crate::cli;
+use crate::engine::Channel;
+
+mod command;
+mod ec;
+mod mock;
+mod qmk;
+mod sysfs;
+
+pub use command::CommandBackend;
+pub use ec::EcBackend;
+pub use mock::{MockBackend, Recording};
+pub use qmk::QmkBackend;
+pub use sysfs::SysfsBackend;
+
+/// Something we can set a keyboard backlight level on
+///
+/// Levels are always 0-100, backends are responsible for scaling them if the hardware uses
+/// a different range.
+pub trait Backlight {
+    /// Short name for log messages
+    fn name(&self) -> &str;
+
+    /// Open whatever handle is needed to talk to the backlight
+    ///
+    /// This is called before every change, so it should be cheap if the handle is already open.
+    fn acquire(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Close any handle opened by [`Backlight::acquire`]
+    ///
+    /// See DEV_NOTES.md, we don't want to monopolize the hardware between fades.
+    fn release(&mut self) {}
+
+    /// Set the backlight level (0-100)
+    fn set_level(&mut self, level: u8) -> Result<()>;
+
+    /// Read back the current backlight level (0-100)
+    ///
+    /// Returns `Ok(None)` if the backend can't read the level.
+    fn level(&mut self) -> Result<Option<u8>> {
+        Ok(None)
+    }
+}
+
+/// A backend, and what we last set it to
+pub struct Sink {
+    pub backend: Box<dyn Backlight>,
+    /// What we last set this sink to, or `None` if we don't know
+    pub current: Option<u8>,
+    /// Whether the last set failed, so we don't spam the log about it
+    failing: bool,
+}
+
+impl Sink {
+    pub fn new(backend: Box<dyn Backlight>) -> Self {
+        Sink {
+            backend,
+            current: None,
+            failing: false,
+        }
+    }
+
+    /// Set this sink's level
+    ///
+    /// Errors get logged once, and only returned if `fatal` is set. Either way the backend is
+    /// released, so the next try starts afresh, e.x. reopening a keyboard that was unplugged.
+    pub fn set(&mut self, level: u8, fatal: bool) -> Result<()> {
+        let res = self.backend.acquire().and_then(|_| self.backend.set_level(level));
+        if res.is_err() {
+            self.backend.release();
+        }
+        match res {
+            Ok(()) => {
+                if self.failing {
+                    info!("{} backlight is working again", self.backend.name());
+                }
+                self.current = Some(level);
+                self.failing = false;
+                Ok(())
+            },
+            Err(e) if fatal => Err(e),
+            Err(e) => {
+                if !self.failing {
+                    error!("error setting {} backlight: {e}", self.backend.name());
+                }
+                self.failing = true;
+                Ok(())
+            },
+        }
+    }
+
+    /// Whether the last set failed
+    pub fn is_failing(&self) -> bool {
+        self.failing
+    }
+
+    /// Read this sink's level
+    pub fn read(&mut self) -> Result<Option<u8>> {
+        self.backend.acquire()?;
+        Ok(self.backend.level()?.map(|l| l.min(100)))
+    }
+}
+
+/// Build the sinks selected on the command line, and the engine channels that go with them
+///
+/// This doesn't need a runtime, so it can also be used from the panic hook.
+pub fn sinks_from_args(args: &cli::Args) -> Result<(Vec<Sink>, Vec<Channel>)> {
+    let recording = match args.record {
+        Some(ref path) if args.dry_run => Some(Recording::create(path)?),
+        _ => None,
+    };
+    let mut sinks = vec![];
+    let mut channels = vec![];
+    for (i, spec) in args.sink_specs().iter().enumerate() {
+        let channel = Channel::from(spec);
+        let backend: Box<dyn Backlight> = if args.dry_run {
+            let name = format!("{:?}#{i}", spec.backend).to_lowercase();
+            Box::new(MockBackend::new(name, channel.scaled(args.brightness), recording.clone()))
+        } else {
+            build(spec, args)?
+        };
+        info!("using {} backend", backend.name());
+        sinks.push(Sink::new(backend));
+        channels.push(channel);
+    }
+    Ok((sinks, channels))
+}
+
+/// Build the backend for one sink
+fn build(spec: &cli::SinkSpec, args: &cli::Args) -> Result<Box<dyn Backlight>> {
+    Ok(match spec.backend {
+        cli::Backend::Ec => match spec.driver.unwrap_or(args.driver) {
+            cli::EcDriver::Ectool => Box::new(CommandBackend::ectool(args)),
+            driver => Box::new(EcBackend::new(driver.as_drivertype()?)),
+        },
+        cli::Backend::Command => {
+            let set = args.set_command.as_deref()
+                .ok_or_else(|| anyhow::anyhow!("the command backend needs --set-command"))?;
+            Box::new(CommandBackend::template(set, args.get_command.as_deref())?)
+        },
+        cli::Backend::Sysfs => Box::new(SysfsBackend::new(&args.sysfs_root, spec.led.as_deref().or(args.led.as_deref()))?),
+        cli::Backend::Qmk => Box::new(QmkBackend::new(
+            spec.hidraw.as_deref().or(args.hidraw.as_deref()),
+            spec.qmk_channel.unwrap_or(args.qmk_channel),
+        )?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Fails while `unplugged` is set, counting how many times it's been released
+    struct Flaky {
+        unplugged: Arc<Mutex<bool>>,
+        releases: Arc<Mutex<u32>>,
+    }
+
+    impl Backlight for Flaky {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn release(&mut self) {
+            *self.releases.lock().unwrap() += 1;
+        }
+
+        fn set_level(&mut self, _level: u8) -> Result<()> {
+            if *self.unplugged.lock().unwrap() {
+                anyhow::bail!("No such device");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sink_survives_unplugging() {
+        let unplugged = Arc::new(Mutex::new(false));
+        let releases = Arc::new(Mutex::new(0));
+        let mut sink = Sink::new(Box::new(Flaky { unplugged: unplugged.clone(), releases: releases.clone() }));
+        sink.set(50, false).unwrap();
+        assert_eq!(sink.current, Some(50));
+
+        *unplugged.lock().unwrap() = true;
+        assert!(sink.set(20, false).is_ok());
+        assert!(sink.is_failing());
+        assert_eq!(sink.current, Some(50));
+        // so it gets opened again next time
+        assert_eq!(*releases.lock().unwrap(), 1);
+        assert!(sink.set(20, true).is_err());
+
+        *unplugged.lock().unwrap() = false;
+        sink.set(20, false).unwrap();
+        assert!(!sink.is_failing());
+        assert_eq!(sink.current, Some(20));
+    }
+}
@@ -0,0 +1,249 @@
+//! Framework 16 QMK keyboard backend, via raw HID
+//!
+//! The Framework 16 input modules run QMK, and their backlight is set with VIA's custom value
+//! commands over the raw HID interface (usage page 0xFF60), not through the EC.
+//!
+//! https://www.caniusevia.com/docs/specification
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use rustix::event::{poll, PollFd, PollFlags};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::Backlight;
+use crate::cli::QmkChannel;
+
+pub const HIDRAW_DIR: &str = "/sys/class/hidraw";
+
+const FRAMEWORK_VID: u32 = 0x32ac;
+/// ANSI, RGB macropad, numpad, ISO, JIS
+const FRAMEWORK_PIDS: &[u32] = &[0x0012, 0x0013, 0x0014, 0x0018, 0x0019];
+
+/// `Usage Page (0xFF60)` as it shows up in a report descriptor
+const VIA_USAGE_PAGE: [u8; 3] = [0x06, 0x60, 0xff];
+
+const VIA_REPORT_LEN: usize = 32;
+const VIA_CUSTOM_SET_VALUE: u8 = 0x07;
+const VIA_CUSTOM_GET_VALUE: u8 = 0x08;
+/// Value id for brightness, the same on every lighting channel
+const VIA_BRIGHTNESS: u8 = 0x01;
+
+/// How long to wait for the keyboard to answer a get value command
+const READ_TIMEOUT_MS: i32 = 100;
+
+impl QmkChannel {
+    fn id(self) -> u8 {
+        match self {
+            QmkChannel::Backlight => 1,
+            QmkChannel::RgbMatrix => 3,
+            QmkChannel::LedMatrix => 5,
+        }
+    }
+}
+
+pub struct QmkBackend {
+    name: String,
+    path: PathBuf,
+    channel: QmkChannel,
+    file: Option<File>,
+}
+
+impl QmkBackend {
+    /// Use the hidraw node at `path`, or find a Framework input module if `path` is `None`
+    ///
+    /// `path` doesn't have to be a real hidraw device, reports are appended to regular files.
+    pub fn new(path: Option<&Path>, channel: QmkChannel) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => discover(Path::new(HIDRAW_DIR))?,
+        };
+        let name = path.file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "qmk".to_string());
+        info!("using qmk keyboard at {} ({channel:?} channel)", path.display());
+        Ok(QmkBackend {
+            name,
+            path,
+            channel,
+            file: None,
+        })
+    }
+
+    fn file(&mut self) -> Result<&mut File> {
+        self.acquire()?;
+        self.file.as_mut().ok_or_else(|| anyhow!("{} isn't open", self.path.display()))
+    }
+
+    fn send(&mut self, command: u8, data: &[u8]) -> Result<()> {
+        let report = via_report(command, self.channel.id(), data);
+        self.file()?.write_all(&report)
+            .with_context(|| format!("failed to write to {}", self.path.display()))
+    }
+}
+
+impl Backlight for QmkBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn acquire(&mut self) -> Result<()> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(&self.path)
+                .with_context(|| format!("failed to open {}", self.path.display()))?;
+            self.file = Some(file);
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        self.file = None;
+    }
+
+    fn set_level(&mut self, level: u8) -> Result<()> {
+        self.send(VIA_CUSTOM_SET_VALUE, &[VIA_BRIGHTNESS, scale_to_raw(level)])
+    }
+
+    fn level(&mut self) -> Result<Option<u8>> {
+        self.send(VIA_CUSTOM_GET_VALUE, &[VIA_BRIGHTNESS])?;
+        let channel = self.channel.id();
+        let file = self.file()?;
+        if poll(&mut [PollFd::new(&*file, PollFlags::IN)], READ_TIMEOUT_MS)? == 0 {
+            debug!("no response to get value command");
+            return Ok(None);
+        }
+        let mut buf = [0u8; VIA_REPORT_LEN];
+        let len = file.read(&mut buf)?;
+        if len < 4 {
+            // e.x. a regular file standing in for a hidraw node
+            debug!("short response to get value command: {:?}", &buf[..len]);
+            return Ok(None);
+        }
+        if buf[..3] != [VIA_CUSTOM_GET_VALUE, channel, VIA_BRIGHTNESS] {
+            anyhow::bail!("unexpected response to get value command: {:?}", &buf[..len]);
+        }
+        Ok(Some(scale_from_raw(buf[3])))
+    }
+}
+
+/// Build a VIA report, with the leading 0 report id hidraw wants
+fn via_report(command: u8, channel: u8, data: &[u8]) -> [u8; VIA_REPORT_LEN + 1] {
+    let mut report = [0u8; VIA_REPORT_LEN + 1];
+    report[1] = command;
+    report[2] = channel;
+    report[3..3 + data.len()].copy_from_slice(data);
+    report
+}
+
+/// Find the raw HID interface of a Framework input module
+fn discover(hidraw_dir: &Path) -> Result<PathBuf> {
+    let mut candidates = vec![];
+    for entry in fs::read_dir(hidraw_dir).with_context(|| format!("failed to read {}", hidraw_dir.display()))? {
+        let entry = entry?;
+        let device = entry.path().join("device");
+        let Ok(uevent) = fs::read_to_string(device.join("uevent")) else { continue; };
+        let Some((vid, pid)) = parse_hid_id(&uevent) else { continue; };
+        if vid != FRAMEWORK_VID || !FRAMEWORK_PIDS.contains(&pid) {
+            continue;
+        }
+        let Ok(descriptor) = fs::read(device.join("report_descriptor")) else { continue; };
+        if descriptor.windows(VIA_USAGE_PAGE.len()).any(|w| w == VIA_USAGE_PAGE) {
+            candidates.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    candidates.sort();
+    debug!("qmk raw hid candidates: {candidates:?}");
+    let name = candidates.into_iter().next()
+        .ok_or_else(|| anyhow!("no Framework QMK keyboard found in {}", hidraw_dir.display()))?;
+    Ok(Path::new("/dev").join(name))
+}
+
+/// Parse `HID_ID=0003:000032AC:00000012` out of a uevent file into (vendor, product)
+fn parse_hid_id(uevent: &str) -> Option<(u32, u32)> {
+    let id = uevent.lines().find_map(|line| line.strip_prefix("HID_ID="))?;
+    let mut parts = id.split(':').skip(1);
+    let vid = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let pid = u32::from_str_radix(parts.next()?, 16).ok()?;
+    Some((vid, pid))
+}
+
+/// 0-100 to 0-255, rounded
+fn scale_to_raw(level: u8) -> u8 {
+    ((level.min(100) as u32 * 255 + 50) / 100) as u8
+}
+
+/// 0-255 to 0-100, rounded
+fn scale_from_raw(raw: u8) -> u8 {
+    ((raw as u32 * 100 + 127) / 255) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const FRAMEWORK_UEVENT: &str = "DRIVER=hid-generic\nHID_ID=0003:000032AC:00000012\nHID_NAME=Framework Laptop 16 Keyboard Module - ANSI\n";
+
+    #[test]
+    fn set_level_writes_via_reports() {
+        let dir = TempDir::new();
+        let path = dir.write("hidraw0", "");
+        let mut backend = QmkBackend::new(Some(&path), QmkChannel::RgbMatrix).unwrap();
+        backend.set_level(100).unwrap();
+        backend.set_level(50).unwrap();
+
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), 2 * (VIA_REPORT_LEN + 1));
+        let mut expected = [0u8; VIA_REPORT_LEN + 1];
+        expected[..5].copy_from_slice(&[0x00, 0x07, 0x03, 0x01, 255]);
+        assert_eq!(written[..VIA_REPORT_LEN + 1], expected);
+        expected[4] = 128;
+        assert_eq!(written[VIA_REPORT_LEN + 1..], expected);
+    }
+
+    #[test]
+    fn parses_hid_id() {
+        assert_eq!(parse_hid_id(FRAMEWORK_UEVENT), Some((0x32ac, 0x0012)));
+        assert_eq!(parse_hid_id("HID_ID=0003:0000046D:0000C52B"), Some((0x046d, 0xc52b)));
+        assert_eq!(parse_hid_id("DRIVER=hid-generic\n"), None);
+        assert_eq!(parse_hid_id("HID_ID=0003:nope"), None);
+    }
+
+    #[test]
+    fn discovers_via_interface() {
+        let dir = TempDir::new();
+        // some other keyboard
+        dir.write("hidraw0/device/uevent", "HID_ID=0003:0000046D:0000C52B\n");
+        fs::write(dir.path().join("hidraw0/device/report_descriptor"), VIA_USAGE_PAGE).unwrap();
+        // the keyboard's regular interface
+        dir.write("hidraw1/device/uevent", FRAMEWORK_UEVENT);
+        dir.write("hidraw1/device/report_descriptor", "\x05\x01\x09\x06");
+        // and its raw hid one
+        dir.write("hidraw2/device/uevent", FRAMEWORK_UEVENT);
+        fs::write(dir.path().join("hidraw2/device/report_descriptor"), [0x06, 0x60, 0xff, 0x09, 0x61]).unwrap();
+        assert_eq!(discover(dir.path()).unwrap(), Path::new("/dev/hidraw2"));
+    }
+
+    #[test]
+    fn no_framework_keyboard_is_an_error() {
+        let dir = TempDir::new();
+        dir.write("hidraw0/device/uevent", "HID_ID=0003:0000046D:0000C52B\n");
+        assert!(discover(dir.path()).is_err());
+    }
+
+    #[test]
+    fn raw_scaling_round_trips() {
+        assert_eq!(scale_to_raw(0), 0);
+        assert_eq!(scale_to_raw(100), 255);
+        assert_eq!(scale_to_raw(150), 255);
+        assert_eq!(scale_from_raw(255), 100);
+        assert_eq!(scale_from_raw(0), 0);
+        for level in 0..=100 {
+            assert_eq!(scale_from_raw(scale_to_raw(level)), level);
+        }
+    }
+}
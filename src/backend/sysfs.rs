@@ -0,0 +1,157 @@
+//! Generic sysfs LED backend
+//!
+//! Works with anything that exposes a keyboard backlight under `/sys/class/leds` (or `class/leds` under --sysfs-root),
+//! e.x. `tpacpi::kbd_backlight`, `dell::kbd_backlight`, `asus::kbd_backlight`
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::Backlight;
+use crate::uleds;
+
+/// Relative to the sysfs root
+const LEDS_DIR: &str = "class/leds";
+
+const KBD_BACKLIGHT_SUFFIX: &str = "::kbd_backlight";
+
+pub struct SysfsBackend {
+    name: String,
+    path: PathBuf,
+    max_brightness: u32,
+    /// Last (raw, level) we wrote, so reading back a coarse LED gives the level we asked for
+    last: Option<(u32, u8)>,
+}
+
+impl SysfsBackend {
+    /// Open the LED called `led` under `sysfs_root`, or find a keyboard backlight if `led` is `None`
+    pub fn new(sysfs_root: &Path, led: Option<&str>) -> Result<Self> {
+        let leds_dir = sysfs_root.join(LEDS_DIR);
+        let name = match led {
+            Some(led) => led.to_string(),
+            None => discover(&leds_dir)?,
+        };
+        let path = leds_dir.join(&name);
+        let max_brightness = read_u32(&path.join("max_brightness"))?;
+        if max_brightness == 0 {
+            anyhow::bail!("{name} has a max_brightness of 0");
+        }
+        info!("using sysfs led {name} (max_brightness={max_brightness})");
+        Ok(SysfsBackend {
+            name,
+            path,
+            max_brightness,
+            last: None,
+        })
+    }
+}
+
+impl Backlight for SysfsBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_level(&mut self, level: u8) -> Result<()> {
+        let raw = scale_to_raw(level, self.max_brightness);
+        fs::write(self.path.join("brightness"), raw.to_string())
+            .with_context(|| format!("failed to set brightness on {}", self.name))?;
+        self.last = Some((raw, level));
+        Ok(())
+    }
+
+    fn level(&mut self) -> Result<Option<u8>> {
+        let raw = read_u32(&self.path.join("brightness"))?;
+        Ok(Some(match self.last {
+            Some((last_raw, level)) if last_raw == raw => level,
+            _ => scale_from_raw(raw, self.max_brightness),
+        }))
+    }
+}
+
+/// Find a keyboard backlight led, skipping our own uleds device
+fn discover(leds_dir: &Path) -> Result<String> {
+    let mut candidates = vec![];
+    for entry in fs::read_dir(leds_dir).with_context(|| format!("failed to read {}", leds_dir.display()))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.ends_with(KBD_BACKLIGHT_SUFFIX) && name != uleds::DEVICE_NAME {
+            candidates.push(name);
+        }
+    }
+    candidates.sort();
+    debug!("keyboard backlight candidates: {candidates:?}");
+    candidates.into_iter().next()
+        .ok_or_else(|| anyhow!("no *{KBD_BACKLIGHT_SUFFIX} led found in {}", leds_dir.display()))
+}
+
+fn read_u32(path: &Path) -> Result<u32> {
+    let s = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    s.trim().parse().with_context(|| format!("bad value in {}: {s:?}", path.display()))
+}
+
+/// 0-100 to 0-`max`, rounded
+fn scale_to_raw(level: u8, max: u32) -> u32 {
+    (level.min(100) as u32 * max + 50) / 100
+}
+
+/// 0-`max` to 0-100, rounded
+fn scale_from_raw(raw: u32, max: u32) -> u8 {
+    ((raw.min(max) * 100 + max / 2) / max) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    /// A fake sysfs tree with one led
+    fn fake_led(name: &str, max_brightness: u32) -> TempDir {
+        let root = TempDir::new();
+        root.write(&format!("class/leds/{name}/max_brightness"), &max_brightness.to_string());
+        root.write(&format!("class/leds/{name}/brightness"), "0");
+        root
+    }
+
+    #[test]
+    fn discovers_keyboard_backlight() {
+        let root = fake_led("tpacpi::kbd_backlight", 2);
+        root.write("class/leds/input3::capslock/max_brightness", "1");
+        root.write(&format!("class/leds/{}/max_brightness", uleds::DEVICE_NAME), "100");
+        let backend = SysfsBackend::new(root.path(), None).unwrap();
+        assert_eq!(backend.name(), "tpacpi::kbd_backlight");
+    }
+
+    #[test]
+    fn no_keyboard_backlight_is_an_error() {
+        let root = fake_led("input3::capslock", 1);
+        assert!(SysfsBackend::new(root.path(), None).is_err());
+    }
+
+    #[test]
+    fn coarse_led_reads_back_written_level() {
+        let root = fake_led("dell::kbd_backlight", 2);
+        let mut backend = SysfsBackend::new(root.path(), Some("dell::kbd_backlight")).unwrap();
+        backend.set_level(40).unwrap();
+        let raw = fs::read_to_string(root.path().join("class/leds/dell::kbd_backlight/brightness")).unwrap();
+        assert_eq!(raw, "1");
+        assert_eq!(backend.level().unwrap(), Some(40));
+
+        // something else changed it, so we get the scaled raw value
+        root.write("class/leds/dell::kbd_backlight/brightness", "2");
+        assert_eq!(backend.level().unwrap(), Some(100));
+    }
+
+    #[test]
+    fn raw_scaling_rounds() {
+        assert_eq!(scale_to_raw(0, 2), 0);
+        assert_eq!(scale_to_raw(24, 2), 0);
+        assert_eq!(scale_to_raw(25, 2), 1);
+        assert_eq!(scale_to_raw(100, 2), 2);
+        assert_eq!(scale_to_raw(200, 255), 255);
+        assert_eq!(scale_from_raw(1, 2), 50);
+        assert_eq!(scale_from_raw(3, 2), 100);
+        for level in 0..=100 {
+            assert_eq!(scale_from_raw(scale_to_raw(level, 255), 255), level);
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use framework_lib::chromium_ec::CrosEcDriverType;
 use anyhow::Result;
 
-#[derive(Clone, Copy, Debug, ValueEnum)]
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize, Deserialize)]
 #[value(rename_all="verbatim")]
 pub enum KeyframeFunction {
     EaseIn,
@@ -46,21 +50,261 @@ impl keyframe::EasingFunction for KeyframeFunction {
     }
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+#[value(rename_all="lowercase")]
+pub enum Backend {
+    /// Framework embedded controller
+    Ec,
+    /// Any keyboard backlight in /sys/class/leds
+    Sysfs,
+    /// Framework 16 QMK keyboard modules, over raw HID
+    Qmk,
+    /// Run a command template, see --set-command
+    Command,
+}
+
+/// One backlight to drive, parsed from `backend[:key=value,...]`
+///
+/// Anything not given falls back to the matching global option.
+#[derive(Clone, Debug)]
+pub struct SinkSpec {
+    pub backend: Backend,
+    /// Multiplier applied to the brightness for this sink
+    pub scale: f32,
+    /// Lowest level this sink is set to while it's lit
+    pub min: u8,
+    pub ease_in: Option<KeyframeFunction>,
+    pub ease_out: Option<KeyframeFunction>,
+    pub driver: Option<EcDriver>,
+    pub led: Option<String>,
+    pub hidraw: Option<PathBuf>,
+    pub qmk_channel: Option<QmkChannel>,
+}
+
+impl SinkSpec {
+    pub fn new(backend: Backend) -> Self {
+        SinkSpec {
+            backend,
+            scale: 1.0,
+            min: 0,
+            ease_in: None,
+            ease_out: None,
+            driver: None,
+            led: None,
+            hidraw: None,
+            qmk_channel: None,
+        }
+    }
+}
+
+fn parse_value_enum<T: ValueEnum>(key: &str, value: &str) -> Result<T, String> {
+    T::from_str(value, true).map_err(|_| format!("invalid {key}: {value}"))
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (backend, options) = s.split_once(':').unwrap_or((s, ""));
+        let mut spec = SinkSpec::new(parse_value_enum("backend", backend)?);
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = option.split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {option}"))?;
+            match key {
+                "scale" => spec.scale = value.parse::<f32>()
+                    .ok().filter(|scale| *scale >= 0.0)
+                    .ok_or_else(|| format!("invalid scale: {value}"))?,
+                "min" => spec.min = value.parse::<u8>()
+                    .ok().filter(|min| *min <= 100)
+                    .ok_or_else(|| format!("invalid min: {value}"))?,
+                "ease-in" => spec.ease_in = Some(parse_value_enum(key, value)?),
+                "ease-out" => spec.ease_out = Some(parse_value_enum(key, value)?),
+                "driver" => spec.driver = Some(parse_value_enum(key, value)?),
+                "led" => spec.led = Some(value.to_string()),
+                "hidraw" => spec.hidraw = Some(value.into()),
+                "channel" => spec.qmk_channel = Some(parse_value_enum(key, value)?),
+                _ => return Err(format!("unknown sink option {key}")),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// One `--stage` of going idle, parsed from `timeout[:key=value,...]`
+///
+/// Anything not given falls back to the matching global option.
+#[derive(Clone, Debug)]
+pub struct StageSpec {
+    /// Seconds since the last activity
+    pub timeout: f32,
+    /// Percentage of the brightness to fade to
+    pub level: u8,
+    pub fade_out: Option<f32>,
+    pub ease_out: Option<KeyframeFunction>,
+}
+
+impl StageSpec {
+    pub fn new(timeout: f32) -> Self {
+        StageSpec {
+            timeout,
+            level: 0,
+            fade_out: None,
+            ease_out: None,
+        }
+    }
+}
+
+/// The longest any timeout, fade or interval can be, a year
+///
+/// Much more than this and adding it to an `Instant` overflows.
+pub const MAX_SECONDS: f32 = 365.0 * 24.0 * 60.0 * 60.0;
+
+/// `secs` as a [`Duration`], if it's between 0 and [`MAX_SECONDS`]
+pub fn duration(secs: f32) -> Result<Duration, String> {
+    if !(0.0..=MAX_SECONDS).contains(&secs) {
+        return Err(format!("has to be 0-{MAX_SECONDS} seconds (a year), not {secs}"));
+    }
+    Ok(Duration::from_secs_f32(secs))
+}
+
+/// Seconds for `key`, see [`duration`]
+fn parse_seconds(key: &str, value: &str) -> Result<f32, String> {
+    seconds(value).map_err(|e| format!("invalid {key}: {e}"))
+}
+
+/// value_parser for options in seconds
+fn seconds(value: &str) -> Result<f32, String> {
+    let secs = value.parse::<f32>().map_err(|_| format!("expected a number of seconds, not {value}"))?;
+    duration(secs)?;
+    Ok(secs)
+}
+
+/// value_parser for octal file modes, e.x. 660
+fn parse_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok().filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| "expected an octal mode, e.x. 660".to_string())
+}
+
+/// [`seconds`], but not 0, for how often to do something
+fn interval(value: &str) -> Result<f32, String> {
+    let secs = seconds(value)?;
+    if secs == 0.0 {
+        return Err("has to be more than 0 seconds".to_string());
+    }
+    Ok(secs)
+}
+
+impl FromStr for StageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (timeout, options) = s.split_once(':').unwrap_or((s, ""));
+        let mut spec = StageSpec::new(parse_seconds("timeout", timeout)?);
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = option.split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {option}"))?;
+            match key {
+                "level" => spec.level = value.parse::<u8>()
+                    .ok().filter(|level| *level <= 100)
+                    .ok_or_else(|| format!("invalid level: {value}"))?,
+                "fade-out" => spec.fade_out = Some(parse_seconds(key, value)?),
+                "ease-out" => spec.ease_out = Some(parse_value_enum(key, value)?),
+                _ => return Err(format!("unknown stage option {key}")),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// Settings for `--ac-profile`/`--battery-profile`, parsed from `key=value,...`
+///
+/// Anything not given is left as the global options have it.
+#[derive(Clone, Debug, Default)]
+pub struct ProfileSpec {
+    /// Seconds until the (first) idle stage
+    pub timeout: Option<f32>,
+    /// Cap on the brightness
+    pub max_brightness: Option<u8>,
+}
+
+impl FromStr for ProfileSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spec = ProfileSpec::default();
+        for option in s.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = option.split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {option}"))?;
+            match key {
+                "timeout" => spec.timeout = Some(parse_seconds(key, value)?),
+                "max-brightness" => spec.max_brightness = Some(value.parse::<u8>()
+                    .ok().filter(|max| *max <= 100)
+                    .ok_or_else(|| format!("invalid max-brightness: {value}"))?),
+                _ => return Err(format!("unknown profile option {key}")),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+#[value(rename_all="lowercase")]
+pub enum QmkChannel {
+    Backlight,
+    RgbMatrix,
+    LedMatrix,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+#[value(rename_all="lowercase")]
+pub enum AlsMode {
+    /// The user's brightness caps the curve's
+    Ceiling,
+    /// The user's brightness shifts the curve up or down, 50 leaves it as is
+    Offset,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all="lowercase")]
+pub enum TabletPolicy {
+    /// Turn the backlight off while folded
+    Off,
+    /// Don't count key presses as activity while folded, e.x. the keyboard pressed against a table
+    Ignore,
+    /// Carry on like it's a laptop
+    Keep,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+#[value(rename_all="lowercase")]
+pub enum StartupLevel {
+    /// Read the current level and fade from it to --brightness
+    Fade,
+    /// Read the current level and use it instead of --brightness
+    Adopt,
+    /// Jump straight to --brightness
+    Reset,
+}
+
 #[derive(Clone, Copy, Debug, ValueEnum)]
 #[value(rename_all="lowercase")]
 pub enum EcDriver {
     Auto,
     Portio,
-    CrosEc
+    CrosEc,
+    /// Run the ectool binary instead of talking to the EC directly
+    Ectool,
 }
 
 impl EcDriver {
-    pub async fn as_drivertype(&self) -> Result<CrosEcDriverType> {
+    pub fn as_drivertype(&self) -> Result<CrosEcDriverType> {
         Ok(match self {
             EcDriver::Portio => CrosEcDriverType::Portio,
             EcDriver::CrosEc => CrosEcDriverType::CrosEc,
+            EcDriver::Ectool => anyhow::bail!("ectool isn't a framework_lib driver"),
             EcDriver::Auto => {
-                if tokio::fs::try_exists("/dev/cros_ec").await? {
+                if std::path::Path::new("/dev/cros_ec").try_exists()? {
                     CrosEcDriverType::CrosEc
                 } else {
                     CrosEcDriverType::Portio
@@ -70,34 +314,228 @@ impl EcDriver {
     }
 }
 
+/// Talk to a running fwkbd instead of starting one
+///
+/// If fwkbd isn't running, these set the backlight directly with the configured backends.
+#[derive(Subcommand, Clone, Debug)]
+pub enum ClientCommand {
+    /// Show the daemon's state and brightness
+    Status {
+        /// Print the raw JSON response
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Set the (non-idle) brightness
+    Set {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+        level: u8,
+    },
+    /// Light the keyboard up, as if it was touched
+    Wake,
+    /// Fade the keyboard out now, instead of waiting for the timeout
+    Idle,
+    /// Keep the keyboard lit until uninhibited (as many times as it's been inhibited)
+    Inhibit {
+        name: String,
+        /// Why, shown in status
+        #[arg(long)]
+        reason: Option<String>,
+        /// Release it automatically after this many seconds
+        #[arg(long = "for", value_name = "SECONDS", value_parser = seconds)]
+        duration: Option<f32>,
+    },
+    /// Release an inhibitor
+    Uninhibit {
+        name: String,
+    },
+    /// Print events (idle/not idle, brightness changes, fades) as JSON lines as they happen
+    Monitor,
+}
+
 /// Keyboard backlight fade in/out daemon for Framework laptops
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(version, about, long_about = None)]
 #[command(after_help="Easing curves accept any curve name from the keyframes crate:\nhttps://docs.rs/keyframe/latest/keyframe/functions/index.html")]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<ClientCommand>,
+
+    /// Config file with the same options as the command line [default: /etc/fwkbd.toml, if it exists]
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective config (file merged with command line) as TOML, and exit
+    #[arg(long, default_value_t = false)]
+    pub print_config: bool,
+
+    /// Reload the config file whenever it changes, not just on SIGHUP
+    #[arg(long, default_value_t = false)]
+    pub watch_config: bool,
+
+    /// Backend used to set the keyboard backlight
+    #[arg(long, value_enum, default_value_t = Backend::Ec)]
+    pub backend: Backend,
+
+    /// Backlight to drive, as backend[:key=value,...]. Can be repeated, and replaces --backend.
+    /// Keys: scale, min, ease-in, ease-out, driver, led, hidraw, channel
+    #[arg(long = "sink", value_name = "SPEC")]
+    pub sinks: Vec<SinkSpec>,
+
+    /// Don't touch the backlight, just log the levels that would be set
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Record each level set during a dry run to this file
+    #[arg(long, requires = "dry_run")]
+    pub record: Option<PathBuf>,
+
     /// Driver to use to talk to the embedded controller
     #[arg(long, value_enum, default_value_t = EcDriver::Auto)]
     pub driver: EcDriver,
 
+    /// Path to the ectool binary, for --driver ectool
+    #[arg(long, default_value = "ectool")]
+    pub ectool: String,
+
+    /// Interface passed to ectool with --interface
+    #[arg(long, default_value = "lpc")]
+    pub ectool_interface: String,
+
+    /// Device name passed to ectool with --name
+    #[arg(long, default_value = "cros_ec")]
+    pub ectool_name: String,
+
+    /// Command to set the backlight with --backend command, {level} is replaced with 0-100
+    #[arg(long, required_if_eq("backend", "command"))]
+    pub set_command: Option<String>,
+
+    /// Command that prints the current backlight level (0-100), for --backend command
+    #[arg(long)]
+    pub get_command: Option<String>,
+
+    /// LED to drive with the sysfs backend, e.x. tpacpi::kbd_backlight [default: autodetect]
+    #[arg(long)]
+    pub led: Option<String>,
+
     /// Seconds until the keyboard backlight times out
-    #[arg(short, long, default_value_t = 5.0)]
+    #[arg(short, long, default_value_t = 5.0, value_parser = seconds)]
     pub timeout: f32,
 
+    /// Idle stage, as timeout[:key=value,...]. Can be repeated, and replaces --timeout.
+    /// Keys: level (percentage of the brightness to dim to, default 0), fade-out, ease-out
+    /// e.x. --stage 10:level=30 --stage 60 dims to 30% after 10s, then turns off after a minute
+    #[arg(long = "stage", value_name = "SPEC")]
+    pub stages: Vec<StageSpec>,
+
     /// Max brightness setting
     #[arg(short, long, default_value_t = 100)]
     #[arg(value_parser = clap::value_parser!(u8).range(1..=100))]
     pub brightness: u8,
 
+    /// What to do with the backlight level the keyboard is at when we start
+    #[arg(long, value_enum, default_value_t = StartupLevel::Fade)]
+    pub startup: StartupLevel,
+
+    /// Seconds between checks for the backlight being changed by something else (e.x. Fn+Space), 0 to disable
+    #[arg(long, default_value_t = 2.0, value_parser = seconds)]
+    pub poll_interval: f32,
+
+    /// Keep the backlight off while the ambient light sensor reads more than this many lux
+    #[arg(long, value_name = "LUX")]
+    pub als_threshold: Option<f32>,
+
+    /// How far below --als-threshold it has to get before the backlight comes back on, in lux
+    #[arg(long, value_name = "LUX", default_value_t = 10.0)]
+    pub als_hysteresis: f32,
+
+    /// Seconds between ambient light sensor readings
+    #[arg(long, value_name = "SECONDS", default_value_t = 1.0, value_parser = interval)]
+    pub als_interval: f32,
+
+    /// Pick the brightness from the ambient light, as lux:level points, e.x. 0:100,50:60,300:20
+    #[arg(long, value_name = "CURVE")]
+    pub als_curve: Option<crate::als::Curve>,
+
+    /// How the user's brightness (e.x. from uleds) combines with --als-curve
+    #[arg(long, value_enum, default_value_t = AlsMode::Ceiling)]
+    pub als_mode: AlsMode,
+
+    /// Seconds to smooth ambient light readings over for --als-curve, 0 to disable
+    #[arg(long, value_name = "SECONDS", default_value_t = 5.0, value_parser = seconds)]
+    pub als_smoothing: f32,
+
+    /// Settings to use while plugged in, as key=value,... Keys: timeout, max-brightness
+    #[arg(long, value_name = "PROFILE")]
+    pub ac_profile: Option<ProfileSpec>,
+
+    /// Settings to use on battery, e.x. timeout=3,max-brightness=50
+    #[arg(long, value_name = "PROFILE")]
+    pub battery_profile: Option<ProfileSpec>,
+
+    /// Cap the backlight when the battery drops below this percentage, while it's discharging
+    #[arg(long, value_name = "PERCENT")]
+    #[arg(value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub low_battery: Option<u8>,
+
+    /// What to cap the backlight to on low battery, 0 turns it off
+    #[arg(long, value_name = "LEVEL", default_value_t = 0)]
+    #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub low_battery_max: u8,
+
+    /// Lift the low battery cap once charging starts, instead of keeping it until the brightness is changed
+    #[arg(long, default_value_t = false)]
+    pub low_battery_restore: bool,
+
+    /// Where sysfs is mounted, e.x. a fake tree for testing
+    #[arg(long, value_name = "PATH", default_value = "/sys")]
+    pub sysfs_root: PathBuf,
+
+    /// hidraw node for the qmk backend [default: autodetect]
+    #[arg(long)]
+    pub hidraw: Option<PathBuf>,
+
+    /// QMK lighting feature the qmk backend controls
+    #[arg(long, value_enum, default_value_t = QmkChannel::Backlight)]
+    pub qmk_channel: QmkChannel,
+
     /// Disable the userspace led, even if the module is present
     #[arg(long, default_value_t = false)]
     pub no_uleds: bool,
+
+    /// Unix socket to listen for control commands on
+    #[arg(long, value_name = "PATH", default_value = crate::control::DEFAULT_PATH)]
+    pub socket: PathBuf,
+
+    /// Permissions for the control socket, in octal. Connecting needs write permission.
+    #[arg(long, value_name = "MODE", default_value = "660", value_parser = parse_mode)]
+    pub socket_mode: u32,
+
+    /// Group to own the control socket, as a name or gid, e.x. so `wheel` can use `fwkbd set` [default: fwkbd's own]
+    #[arg(long, value_name = "GROUP")]
+    pub socket_group: Option<String>,
+
+    /// Don't listen on the control socket
+    #[arg(long, default_value_t = false)]
+    pub no_socket: bool,
+
+    /// Don't listen for suspend/resume from logind
+    #[arg(long, default_value_t = false)]
+    pub no_logind: bool,
+
+    /// Serve UPower's KbdBacklight interface on the system bus, for desktop brightness sliders
+    #[arg(long, default_value_t = false)]
+    pub dbus: bool,
+
+    /// Bus name to serve KbdBacklight as. The default only works if upowerd isn't running
+    #[arg(long, value_name = "NAME", default_value = crate::dbus::DEFAULT_NAME)]
+    pub dbus_name: String,
     
     /// How long, in seconds, to fade the keyboard backlight in
-    #[arg(short='i', long, default_value_t = 0.2)]
+    #[arg(short='i', long, default_value_t = 0.2, value_parser = seconds)]
     pub fade_in: f32,
 
     /// How long, in seconds, to fade the keyboard backlight out
-    #[arg(short='o', long, default_value_t = 1.0)]
+    #[arg(short='o', long, default_value_t = 1.0, value_parser = seconds)]
     pub fade_out: f32,
 
     /// Animation curve for fading in
@@ -108,7 +546,31 @@ pub struct Args {
     #[arg(long, value_enum, hide_possible_values=true, default_value_t = KeyframeFunction::EaseOut)]
     pub ease_out: KeyframeFunction,
 
+    /// What to do while a convertible is folded into tablet mode, with the keyboard facing down
+    #[arg(long, value_enum, default_value_t = TabletPolicy::Off)]
+    pub tablet_mode: TabletPolicy,
+
     /// Ignore pointer movements, only consider keyboard movements
     #[arg(long, default_value_t = false)]
     pub ignore_pointer: bool,
-}
\ No newline at end of file
+}
+
+impl Args {
+    /// The `--sink`s, or a single sink built from `--backend` if there weren't any
+    pub fn sink_specs(&self) -> Vec<SinkSpec> {
+        if self.sinks.is_empty() {
+            vec![SinkSpec::new(self.backend)]
+        } else {
+            self.sinks.clone()
+        }
+    }
+
+    /// The `--stage`s, or a single stage turning off after `--timeout` if there weren't any
+    pub fn stage_specs(&self) -> Vec<StageSpec> {
+        if self.stages.is_empty() {
+            vec![StageSpec::new(self.timeout)]
+        } else {
+            self.stages.clone()
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! `fwkbd status`, `fwkbd set`, etc
+//!
+//! These talk to a running daemon over the control socket. If there isn't one, they fall back to
+//! setting the backlight once through the same backends the daemon would use.
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::backend;
+use crate::cli::{Args, ClientCommand};
+use crate::control::{self, Command, Reply, Request, Response, PROTOCOL_VERSION};
+
+pub async fn run(args: &Args, command: &ClientCommand) -> Result<()> {
+    let stream = match UnixStream::connect(&args.socket).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            connect_error(&args.socket, e)?;
+            return tokio::task::block_in_place(|| one_shot(args, command));
+        },
+    };
+
+    let request = match *command {
+        ClientCommand::Status { .. } => Command::Status,
+        ClientCommand::Set { level } => Command::SetBrightness { level },
+        ClientCommand::Wake => Command::Wake,
+        ClientCommand::Idle => Command::Idle,
+        ClientCommand::Inhibit { ref name, ref reason, duration } => Command::Inhibit {
+            name: name.clone(),
+            reason: reason.clone(),
+            duration,
+        },
+        ClientCommand::Uninhibit { ref name } => Command::Uninhibit { name: name.clone() },
+        ClientCommand::Monitor => Command::Subscribe,
+    };
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let (line, response) = send(&mut lines, &mut write, request).await?;
+    match response.reply {
+        Reply::Ok => {},
+        Reply::Status(_) if matches!(command, ClientCommand::Status { json: true }) => println!("{line}"),
+        Reply::Status(status) => print_status(&status),
+        Reply::Error { message } => bail!("fwkbd: {message}"),
+    }
+
+    if let ClientCommand::Monitor = command {
+        while let Some(line) = lines.next_line().await? {
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+/// Send one request, returning the raw response line along with the parsed response
+async fn send(lines: &mut Lines<BufReader<OwnedReadHalf>>, write: &mut OwnedWriteHalf, command: Command) -> Result<(String, Response)> {
+    let mut buf = serde_json::to_vec(&Request { version: PROTOCOL_VERSION, command })?;
+    buf.push(b'\n');
+    write.write_all(&buf).await?;
+    let line = lines.next_line().await?
+        .ok_or_else(|| anyhow!("fwkbd hung up without answering"))?;
+    let response = serde_json::from_str(&line)
+        .with_context(|| format!("bad response from fwkbd: {line}"))?;
+    Ok((line, response))
+}
+
+fn print_status(status: &control::Status) {
+    println!("state: {:?}", status.state);
+    let adjusted = if status.backlight != status.target {
+        format!(", adjusted to {}", status.backlight)
+    } else {
+        String::new()
+    };
+    println!("brightness: {}{adjusted} (currently {}{})", status.target, status.current,
+        if status.fading { ", fading" } else { "" });
+    println!("levels: {:?}", status.levels);
+    if status.stages.len() > 1 {
+        println!("fade in: {}s", status.fade_in);
+        for (i, stage) in status.stages.iter().enumerate() {
+            println!("stage {}{}: {}% after {}s, fading over {}s", i + 1,
+                if status.stage == i + 1 { " (current)" } else { "" },
+                stage.level, stage.timeout, stage.fade_out);
+        }
+    } else {
+        println!("timeout: {}s, fade in: {}s, fade out: {}s", status.timeout, status.fade_in, status.fade_out);
+    }
+    for inhibitor in &status.inhibitors {
+        let mut line = format!("inhibited by {}: {}", inhibitor.name, inhibitor.reason);
+        if inhibitor.count > 1 {
+            line += &format!(" (x{})", inhibitor.count);
+        }
+        if let Some(expires_in) = inhibitor.expires_in {
+            line += &format!(", expires in {:.0}s", expires_in.ceil());
+        }
+        println!("{line}");
+    }
+    if status.lid_closed {
+        println!("lid closed, keeping the backlight off");
+    }
+    if status.tablet_mode {
+        println!("in tablet mode");
+    }
+    if let Some(cap) = status.cap {
+        println!("capped at {cap}: {}", status.cap_reason.as_deref().unwrap_or("no reason given"));
+    }
+    if let Some(power) = status.power {
+        println!("power: {power:?}");
+    }
+    if let Some(lux) = status.lux {
+        println!("ambient light: {lux:.0} lux{}", if status.room_bright { ", keeping the backlight off" } else { "" });
+    }
+}
+
+/// Decide what to do about not being able to connect to `socket`: `Ok` to fall back to [`one_shot`],
+/// which is only safe when there's no daemon to fight with
+fn connect_error(socket: &Path, e: io::Error) -> Result<()> {
+    match e.kind() {
+        ErrorKind::NotFound | ErrorKind::ConnectionRefused => {
+            debug!("couldn't connect to {}: {e}", socket.display());
+            Ok(())
+        },
+        ErrorKind::PermissionDenied => {
+            bail!("permission denied connecting to {}, see --socket-mode and --socket-group", socket.display());
+        },
+        _ => Err(e).with_context(|| format!("failed to connect to {}", socket.display())),
+    }
+}
+
+/// Do what we can without a daemon, by talking to the backends directly
+fn one_shot(args: &Args, command: &ClientCommand) -> Result<()> {
+    let (mut sinks, channels) = backend::sinks_from_args(args)?;
+    let level = match *command {
+        ClientCommand::Status { json: true } => bail!("fwkbd isn't running"),
+        ClientCommand::Status { json: false } => {
+            match sinks[0].read()? {
+                Some(level) => println!("fwkbd isn't running, backlight is at {level}"),
+                None => println!("fwkbd isn't running"),
+            }
+            return Ok(());
+        },
+        ClientCommand::Set { level } => level,
+        ClientCommand::Wake => args.brightness,
+        ClientCommand::Idle => 0,
+        ClientCommand::Inhibit { .. } | ClientCommand::Uninhibit { .. } => {
+            bail!("fwkbd isn't running, there's nothing to inhibit")
+        },
+        ClientCommand::Monitor => bail!("fwkbd isn't running"),
+    };
+    debug!("fwkbd isn't running, setting the backlight to {level} directly");
+    for (i, (sink, channel)) in sinks.iter_mut().zip(&channels).enumerate() {
+        sink.set(channel.scaled(level), i == 0)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use clap::Parser;
+
+    /// Parse `fwkbd --dry-run --record <file> <args>`, returning the file too
+    fn dry_run(dir: &TempDir, args: &[&str]) -> (Args, ClientCommand, std::path::PathBuf) {
+        let (socket, record) = (dir.path().join("fwkbd.sock"), dir.path().join("levels.txt"));
+        let mut argv = vec!["fwkbd", "--dry-run", "--socket", socket.to_str().unwrap(), "--record", record.to_str().unwrap()];
+        argv.extend(args);
+        let args = Args::try_parse_from(argv).unwrap();
+        let command = args.command.clone().unwrap();
+        (args, command, record)
+    }
+
+    /// The levels recorded, by sink
+    fn recorded(record: &Path) -> Vec<(String, u8)> {
+        std::fs::read_to_string(record).unwrap().lines().map(|line| {
+            let mut fields = line.split(' ').skip(1);
+            (fields.next().unwrap().to_string(), fields.next().unwrap().parse().unwrap())
+        }).collect()
+    }
+
+    #[test]
+    fn one_shot_levels() {
+        let dir = TempDir::new();
+        let cases: &[(&[&str], u8)] = &[
+            (&["set", "40"], 40),
+            (&["-b", "70", "wake"], 70),
+            (&["idle"], 0),
+        ];
+        for &(argv, level) in cases {
+            let (args, command, record) = dry_run(&dir, argv);
+            one_shot(&args, &command).unwrap();
+            assert_eq!(recorded(&record), vec![("ec#0".to_string(), level)], "{argv:?}");
+        }
+    }
+
+    #[test]
+    fn one_shot_scales_each_sink() {
+        let dir = TempDir::new();
+        let (args, command, record) = dry_run(&dir, &["--sink", "ec", "--sink", "qmk:scale=0.5", "set", "80"]);
+        one_shot(&args, &command).unwrap();
+        assert_eq!(recorded(&record), vec![("ec#0".to_string(), 80), ("qmk#1".to_string(), 40)]);
+    }
+
+    #[test]
+    fn one_shot_needs_daemon() {
+        let dir = TempDir::new();
+        for argv in [&["inhibit", "slides"][..], &["uninhibit", "slides"], &["monitor"], &["status", "--json"]] {
+            let (args, command, record) = dry_run(&dir, argv);
+            assert!(one_shot(&args, &command).is_err(), "{argv:?}");
+            assert_eq!(recorded(&record), vec![], "{argv:?}");
+        }
+        let (args, command, _) = dry_run(&dir, &["status"]);
+        one_shot(&args, &command).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn falls_back_without_daemon() {
+        let dir = TempDir::new();
+        let (args, command, record) = dry_run(&dir, &["set", "30"]);
+        run(&args, &command).await.unwrap();
+        assert_eq!(recorded(&record), vec![("ec#0".to_string(), 30)]);
+    }
+
+    #[test]
+    fn only_falls_back_when_nothing_is_listening() {
+        let socket = Path::new("/run/fwkbd.sock");
+        assert!(connect_error(socket, ErrorKind::NotFound.into()).is_ok());
+        assert!(connect_error(socket, ErrorKind::ConnectionRefused.into()).is_ok());
+        let err = connect_error(socket, ErrorKind::PermissionDenied.into()).unwrap_err();
+        assert!(err.to_string().contains("--socket-group"), "{err}");
+        assert!(connect_error(socket, ErrorKind::TimedOut.into()).is_err());
+    }
+
+    #[test]
+    fn inhibit_duration_is_checked() {
+        assert!(Args::try_parse_from(["fwkbd", "inhibit", "slides", "--for", "3600"]).is_ok());
+        assert!(Args::try_parse_from(["fwkbd", "inhibit", "slides", "--for", "1e19"]).is_err());
+        assert!(Args::try_parse_from(["fwkbd", "inhibit", "slides", "--for", "-1"]).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! TOML config file support
+//!
+//! The config file takes the same options as the command line, keyed by their long name:
+//!
+//! ```toml
+//! timeout = 10
+//! fade-out = 2.5
+//! ease-out = "EaseOutCubic"
+//! sink = ["ec", "qmk:scale=0.5"]
+//! ```
+//!
+//! Anything given on the command line wins over the file.
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches};
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use crate::cli::Args;
+
+/// Read if it exists and no `--config` was given
+pub const DEFAULT_PATH: &str = "/etc/fwkbd.toml";
+
+/// Options that only make sense on the command line
+const CLI_ONLY: &[&str] = &["config", "print_config", "watch_config", "help", "version"];
+
+/// Parse `argv`, filling in anything it doesn't set from the config file
+///
+/// Exits the way clap does for `--help`, `--version`, bad arguments and `--print-config`.
+pub fn load(argv: &[OsString]) -> Result<Args> {
+    let matches = matches(argv)?;
+    if matches.get_flag("print_config") {
+        print!("{}", effective(&matches));
+        std::process::exit(0);
+    }
+    Ok(Args::from_arg_matches(&matches)?)
+}
+
+/// `argv` merged with the config file, if there is one
+fn matches(argv: &[OsString]) -> Result<ArgMatches> {
+    let cli_matches = Args::command().get_matches_from(argv);
+    let path = path(cli_matches.get_one::<PathBuf>("config"));
+
+    let matches = match path {
+        Some(ref path) => {
+            let file_args = read(path, &cli_matches)?;
+            let mut merged = vec![argv.first().cloned().unwrap_or_else(|| "fwkbd".into())];
+            merged.extend(file_args);
+            merged.extend(argv.iter().skip(1).cloned());
+            Args::command().try_get_matches_from(merged)
+                .map_err(|e| anyhow!("{}: {e}", path.display()))?
+        },
+        None => cli_matches,
+    };
+    Ok(matches)
+}
+
+/// The config file to use: `config` if given, otherwise the default if it exists
+pub fn path(config: Option<&PathBuf>) -> Option<PathBuf> {
+    match config {
+        Some(path) => Some(path.clone()),
+        None => Some(PathBuf::from(DEFAULT_PATH)).filter(|path| path.exists()),
+    }
+}
+
+/// Turn a config file into command line arguments, skipping anything already in `cli_matches`
+fn read(path: &Path, cli_matches: &ArgMatches) -> Result<Vec<OsString>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let table: toml::Table = contents.parse()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+
+    let command = Args::command();
+    let mut args = vec![];
+    for (key, value) in table {
+        let arg = command.get_arguments()
+            .find(|arg| arg.get_long() == Some(&key) && !CLI_ONLY.contains(&arg.get_id().as_str()))
+            .ok_or_else(|| anyhow!("{}: unknown option {key}", path.display()))?;
+        if cli_matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let flag = format!("--{key}");
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(set) if !arg.get_action().takes_values() => if set {
+                    args.push(flag.clone().into());
+                },
+                toml::Value::String(s) => args.extend([flag.clone().into(), s.into()]),
+                toml::Value::Integer(i) => args.extend([flag.clone().into(), i.to_string().into()]),
+                toml::Value::Float(f) => args.extend([flag.clone().into(), f.to_string().into()]),
+                value => anyhow::bail!("{}: bad value for {key}: {value}", path.display()),
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Render the merged options as a config file
+fn effective(matches: &ArgMatches) -> String {
+    let command = Args::command();
+    let mut table = toml::Table::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else { continue; };
+        if CLI_ONLY.contains(&id) {
+            continue;
+        }
+        if !arg.get_action().takes_values() {
+            table.insert(long.to_string(), toml::Value::Boolean(matches.get_flag(id)));
+            continue;
+        }
+        let Some(raw) = matches.get_raw(id) else { continue; };
+        let values: Vec<toml::Value> = raw.map(|v| to_toml(&v.to_string_lossy())).collect();
+        if matches!(arg.get_action(), ArgAction::Append) {
+            table.insert(long.to_string(), toml::Value::Array(values));
+        } else if let Some(value) = values.into_iter().next() {
+            table.insert(long.to_string(), value);
+        }
+    }
+    toml::to_string(&table).expect("toml table should always serialize")
+}
+
+/// Numbers as numbers, everything else as a string
+fn to_toml(value: &str) -> toml::Value {
+    if let Ok(i) = value.parse::<i64>() {
+        toml::Value::Integer(i)
+    } else if let Ok(f) = value.parse::<f64>() {
+        toml::Value::Float(f)
+    } else {
+        toml::Value::String(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn argv(args: &[&str]) -> Vec<OsString> {
+        std::iter::once("fwkbd").chain(args.iter().copied()).map(OsString::from).collect()
+    }
+
+    /// Parse `args` with `file` as the config file
+    fn load_with(file: &str, args: &[&str]) -> Result<Args> {
+        let dir = TempDir::new();
+        let path = dir.write("fwkbd.toml", file);
+        let mut full = vec!["--config", path.to_str().unwrap()];
+        full.extend(args);
+        Ok(Args::from_arg_matches(&matches(&argv(&full))?)?)
+    }
+
+    #[test]
+    fn file_fills_in_defaults() {
+        let args = load_with("timeout = 10\nfade-out = 2.5\ndry-run = true\nsink = [\"ec\", \"qmk:scale=0.5\"]\n", &[]).unwrap();
+        assert_eq!(args.timeout, 10.0);
+        assert_eq!(args.fade_out, 2.5);
+        assert!(args.dry_run);
+        assert_eq!(args.sinks.len(), 2);
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let file = "timeout = 10\nfade-out = 2.5\nsink = [\"ec\", \"qmk:scale=0.5\"]\n";
+        let args = load_with(file, &["--timeout", "3", "--sink", "sysfs"]).unwrap();
+        assert_eq!(args.timeout, 3.0);
+        assert_eq!(args.fade_out, 2.5);
+        // repeatable options are replaced, not appended to
+        assert_eq!(args.sinks.len(), 1);
+        assert!(matches!(args.sinks[0].backend, crate::cli::Backend::Sysfs));
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let err = load_with("timeout = 10\nfade-outt = 2.5\n", &[]).unwrap_err();
+        assert!(err.to_string().contains("unknown option fade-outt"), "{err}");
+        // command line only
+        assert!(load_with("print-config = true\n", &[]).is_err());
+    }
+
+    #[test]
+    fn bad_values_are_errors() {
+        assert!(load_with("timeout = \"soon\"\n", &[]).is_err());
+        assert!(load_with("timeout = { seconds = 5 }\n", &[]).is_err());
+        assert!(load_with("timeout = -1\n", &[]).is_err());
+        assert!(load_with("fade-in = 1e30\n", &[]).is_err());
+        // fits in a Duration, but not on top of an Instant
+        assert!(load_with("timeout = 1e19\n", &[]).is_err());
+        assert!(load_with("als-interval = 1e19\n", &[]).is_err());
+        assert!(load_with("stage = [\"1e19\"]\n", &[]).is_err());
+        assert!(load_with("ac-profile = \"timeout=1e19\"\n", &[]).is_err());
+        assert!(load_with("timeout = 31536000\n", &[]).is_ok());
+        assert!(load_with("als-interval = 0\n", &[]).is_err());
+        assert!(load_with("poll-interval = 0\n", &[]).is_ok());
+    }
+
+    #[test]
+    fn print_config_round_trips() {
+        let file = "timeout = 10\nease-in = \"EaseInQuad\"\nsink = [\"ec\", \"qmk:scale=0.5\"]\n";
+        let dir = TempDir::new();
+        let path = dir.write("fwkbd.toml", file);
+        let first = matches(&argv(&["--config", path.to_str().unwrap(), "--fade-out", "2.5", "--dry-run"])).unwrap();
+        let dumped = effective(&first);
+
+        let path = dir.write("dumped.toml", &dumped);
+        let second = matches(&argv(&["--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(effective(&second), dumped);
+
+        let (mut first, mut second) = (Args::from_arg_matches(&first).unwrap(), Args::from_arg_matches(&second).unwrap());
+        first.config = None;
+        second.config = None;
+        assert_eq!(format!("{first:?}"), format!("{second:?}"));
+    }
+}
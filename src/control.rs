@@ -0,0 +1,504 @@
+//! Unix socket control interface
+//!
+//! Clients send one JSON request per line and get one JSON response per line back, e.x.
+//!
+//! ```text
+//! > {"version":1,"command":"status"}
+//! < {"version":1,"result":"status","state":"not-idle","target":80,"current":80,...}
+//! > {"version":1,"command":"set-brightness","level":40}
+//! < {"version":1,"result":"ok"}
+//! > {"version":1,"command":"set-timing","timeout":10,"ease-out":"EaseOutCubic"}
+//! < {"version":1,"result":"ok"}
+//! > {"version":1,"command":"idle"}
+//! < {"version":1,"result":"ok"}
+//! > {"version":1,"command":"inhibit","name":"slides","reason":"presenting","duration":3600}
+//! < {"version":1,"result":"ok"}
+//! ```
+//!
+//! After a `subscribe` request, events are streamed on the same connection as they happen, and
+//! can be told apart from responses by having an `event` instead of a `result`:
+//!
+//! ```text
+//! > {"version":1,"command":"subscribe"}
+//! < {"version":1,"result":"ok"}
+//! < {"version":1,"event":"state","state":"idle"}
+//! < {"version":1,"event":"fade-started","from":80,"to":0}
+//! < {"version":1,"event":"fade-finished","level":0}
+//! < {"version":1,"event":"brightness","level":40,"source":"uleds"}
+//! ```
+//!
+//! The socket is created with `--socket-mode` (0660 by default) and `--socket-group`, and connecting
+//! to it needs write permission, so by default only root can use it.
+//!
+//! Requests from here (and D-Bus) are handed to the main loop as [`Pending`]s, so they go through
+//! the same engine events as everything else.
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, oneshot};
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cli::KeyframeFunction;
+use crate::engine::{Notice, State};
+use crate::power::PowerSource;
+
+pub const DEFAULT_PATH: &str = "/run/fwkbd.sock";
+
+/// Bumped whenever a request or response changes incompatibly
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub version: u32,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Command {
+    Status,
+    /// Set the user's brightness (0-100), same as writing to the uleds device
+    SetBrightness { level: u8 },
+    /// Change the timing, anything left out stays as it is
+    ///
+    /// `timeout`, `fade-out` and `ease-out` are the first idle stage's.
+    #[serde(rename_all = "kebab-case")]
+    SetTiming {
+        /// Seconds
+        timeout: Option<f32>,
+        /// Seconds
+        fade_in: Option<f32>,
+        /// Seconds
+        fade_out: Option<f32>,
+        ease_in: Option<KeyframeFunction>,
+        ease_out: Option<KeyframeFunction>,
+    },
+    /// Go idle now
+    Idle,
+    /// Act like the user touched the keyboard
+    Wake,
+    /// Keep the keyboard lit until released as many times as it's been taken
+    Inhibit {
+        name: String,
+        reason: Option<String>,
+        /// Seconds until it goes away on its own
+        duration: Option<f32>,
+    },
+    /// Release an inhibitor once
+    Uninhibit { name: String },
+    /// Stream events on this connection until it's closed
+    Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub version: u32,
+    #[serde(flatten)]
+    pub reply: Reply,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum Reply {
+    Ok,
+    Status(Status),
+    Error { message: String },
+}
+
+impl Reply {
+    pub fn error(message: impl ToString) -> Self {
+        Reply::Error { message: message.to_string() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Status {
+    pub state: State,
+    /// The user's brightness
+    pub target: u8,
+    /// What we fade in to, the user's brightness adjusted for the ambient light and power profile
+    #[serde(default)]
+    pub backlight: u8,
+    /// Where the backlight is right now
+    pub current: u8,
+    /// Per-sink levels
+    pub levels: Vec<u8>,
+    pub fading: bool,
+    /// Seconds, of the first idle stage
+    pub timeout: f32,
+    /// Seconds
+    pub fade_in: f32,
+    /// Seconds, of the first idle stage
+    pub fade_out: f32,
+    /// How many idle stages we've gone through, 0 while not idle
+    #[serde(default)]
+    pub stage: usize,
+    #[serde(default)]
+    pub stages: Vec<StageStatus>,
+    #[serde(default)]
+    pub inhibitors: Vec<InhibitorStatus>,
+    /// Last ambient light sensor reading
+    #[serde(default)]
+    pub lux: Option<f32>,
+    /// Whether the backlight is being kept off for the ambient light
+    #[serde(default)]
+    pub room_bright: bool,
+    /// What we're running on, if we're watching for power profiles
+    #[serde(default)]
+    pub power: Option<PowerSource>,
+    /// Most the backlight can be right now, e.x. on low battery
+    #[serde(default)]
+    pub cap: Option<u8>,
+    /// Why there's a cap
+    #[serde(default)]
+    pub cap_reason: Option<String>,
+    #[serde(default)]
+    pub lid_closed: bool,
+    #[serde(default)]
+    pub tablet_mode: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct StageStatus {
+    /// Seconds
+    pub timeout: f32,
+    /// Percentage of the user's brightness
+    pub level: u8,
+    /// Seconds
+    pub fade_out: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct InhibitorStatus {
+    pub name: String,
+    pub reason: String,
+    pub count: u32,
+    /// Seconds until it goes away on its own
+    pub expires_in: Option<f32>,
+}
+
+/// Where a brightness change came from
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Uleds,
+    Socket,
+    Dbus,
+    /// Something else changed the backlight, e.x. Fn+Space
+    External,
+}
+
+/// Sent to subscribers as things happen
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Notification {
+    State { state: State },
+    /// The user's brightness changed
+    Brightness { level: u8, source: Source },
+    FadeStarted { from: u8, to: u8 },
+    FadeFinished { level: u8 },
+}
+
+impl From<Notice> for Notification {
+    fn from(notice: Notice) -> Self {
+        match notice {
+            Notice::State(state) => Notification::State { state },
+            Notice::FadeStarted { from, to } => Notification::FadeStarted { from, to },
+            Notice::FadeFinished { level } => Notification::FadeFinished { level },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EventMessage {
+    pub version: u32,
+    #[serde(flatten)]
+    pub event: Notification,
+}
+
+/// A request waiting for the main loop to answer it
+pub struct Pending {
+    pub command: Command,
+    pub source: Source,
+    reply: oneshot::Sender<Reply>,
+}
+
+impl Pending {
+    pub fn reply(self, reply: Reply) {
+        // the client hanging up isn't our problem
+        let _ = self.reply.send(reply);
+    }
+}
+
+/// Sends requests to the main loop
+#[derive(Clone)]
+pub struct Requester {
+    tx: mpsc::Sender<Pending>,
+    events: broadcast::Sender<Notification>,
+    source: Source,
+}
+
+impl Requester {
+    /// Hand `command` to the main loop and wait for its reply
+    pub async fn request(&self, command: Command) -> Result<Reply> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Pending { command, source: self.source, reply }).await
+            .map_err(|_| anyhow!("main loop isn't taking requests"))?;
+        Ok(rx.await?)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.events.subscribe()
+    }
+}
+
+/// The main loop's end of the requests
+pub struct Requests {
+    tx: mpsc::Sender<Pending>,
+    rx: mpsc::Receiver<Pending>,
+    events: broadcast::Sender<Notification>,
+}
+
+impl Requests {
+    pub fn new(events: broadcast::Sender<Notification>) -> Self {
+        let (tx, rx) = mpsc::channel(8);
+        Requests { tx, rx, events }
+    }
+
+    /// Something for `source` to send requests with
+    pub fn requester(&self, source: Source) -> Requester {
+        Requester {
+            tx: self.tx.clone(),
+            events: self.events.clone(),
+            source,
+        }
+    }
+
+    pub async fn next(&mut self) -> Pending {
+        // we hold a sender, so this never runs dry
+        self.rx.recv().await.expect("request channel closed")
+    }
+}
+
+/// Removes the socket when dropped
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Listen on `path` with permissions `mode` and optionally owned by `group`,
+    /// replacing a stale socket left over from a previous run
+    pub fn bind(path: &Path, mode: u32, group: Option<&str>, requester: Requester) -> Result<Self> {
+        let gid = group.map(group_id).transpose()?;
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                bail!("{} is in use, is fwkbd already running?", path.display());
+            }
+            fs::remove_file(path).with_context(|| format!("failed to remove {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path).with_context(|| format!("failed to bind {}", path.display()))?;
+        // from here on, dropping it cleans up the socket if setting it up fails
+        let socket = ControlSocket {
+            path: path.to_path_buf(),
+        };
+        if let Some(gid) = gid {
+            std::os::unix::fs::chown(path, None, Some(gid))
+                .with_context(|| format!("failed to chown {} to group {gid}", path.display()))?;
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to chmod {}", path.display()))?;
+        info!("listening on {} (mode {mode:o}, group {gid:?})", path.display());
+        tokio::spawn(accept(listener, requester));
+        Ok(socket)
+    }
+}
+
+/// A group name or gid, to a gid
+fn group_id(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let groups = fs::read_to_string("/etc/group").context("failed to read /etc/group")?;
+    find_group(&groups, group).ok_or_else(|| anyhow!("no such group: {group}"))
+}
+
+/// Look `name` up in the contents of `/etc/group`
+fn find_group(groups: &str, name: &str) -> Option<u32> {
+    groups.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+async fn accept(listener: UnixListener, requester: Requester) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let requester = requester.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, requester).await {
+                        debug!("control connection closed: {e}");
+                    }
+                });
+            },
+            Err(e) => {
+                error!("error accepting control connection: {e}");
+                // e.x. out of fds, don't spin
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            },
+        }
+    }
+}
+
+/// Answer requests from one client (and send it events, if it subscribed) until it hangs up
+async fn serve(stream: UnixStream, requester: Requester) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let mut events = None;
+    loop {
+        let mut buf = tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else { break; };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let reply = match serde_json::from_str::<Request>(&line) {
+                    Ok(request) if request.version != PROTOCOL_VERSION => {
+                        Reply::error(format!("unsupported protocol version {}, expected {PROTOCOL_VERSION}", request.version))
+                    },
+                    Ok(Request { command: Command::Subscribe, .. }) => {
+                        events = Some(requester.subscribe());
+                        Reply::Ok
+                    },
+                    Ok(request) => {
+                        debug!("control request: {:?}", request.command);
+                        requester.request(request.command).await?
+                    },
+                    Err(e) => Reply::error(format!("bad request: {e}")),
+                };
+                serde_json::to_vec(&Response { version: PROTOCOL_VERSION, reply })?
+            },
+            event = next_event(&mut events) => match event {
+                Ok(event) => serde_json::to_vec(&EventMessage { version: PROTOCOL_VERSION, event })?,
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("subscriber fell behind, dropped {missed} events");
+                    continue;
+                },
+                Err(RecvError::Closed) => break,
+            },
+        };
+        buf.push(b'\n');
+        write.write_all(&buf).await?;
+    }
+    Ok(())
+}
+
+/// Wait for the next event, or forever if the client hasn't subscribed
+async fn next_event(events: &mut Option<broadcast::Receiver<Notification>>) -> Result<Notification, RecvError> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Serializes to `expected`, and back to the same thing
+    fn round_trip<T: Serialize + serde::de::DeserializeOwned + std::fmt::Debug>(value: T, expected: serde_json::Value) {
+        let serialized = serde_json::to_value(&value).unwrap();
+        assert_eq!(serialized, expected);
+        let back: T = serde_json::from_value(serialized).unwrap();
+        assert_eq!(format!("{back:?}"), format!("{value:?}"));
+    }
+
+    fn request(command: Command) -> Request {
+        Request { version: PROTOCOL_VERSION, command }
+    }
+
+    #[test]
+    fn requests() {
+        round_trip(request(Command::Status), json!({"version": 1, "command": "status"}));
+        round_trip(request(Command::SetBrightness { level: 40 }), json!({"version": 1, "command": "set-brightness", "level": 40}));
+        round_trip(request(Command::Idle), json!({"version": 1, "command": "idle"}));
+        round_trip(request(Command::Uninhibit { name: "slides".into() }), json!({"version": 1, "command": "uninhibit", "name": "slides"}));
+        round_trip(request(Command::Inhibit { name: "slides".into(), reason: Some("presenting".into()), duration: Some(3600.0) }),
+            json!({"version": 1, "command": "inhibit", "name": "slides", "reason": "presenting", "duration": 3600.0}));
+    }
+
+    #[test]
+    fn optional_fields_can_be_left_out() {
+        let request: Request = serde_json::from_str(r#"{"version":1,"command":"set-timing","timeout":10,"ease-out":"EaseOutCubic"}"#).unwrap();
+        let Command::SetTiming { timeout, fade_in, fade_out, ease_in, ease_out } = request.command else {
+            panic!("expected set-timing, got {:?}", request.command);
+        };
+        assert_eq!((timeout, fade_in, fade_out), (Some(10.0), None, None));
+        assert!(ease_in.is_none());
+        assert!(matches!(ease_out, Some(KeyframeFunction::EaseOutCubic)));
+
+        let request: Request = serde_json::from_str(r#"{"version":1,"command":"inhibit","name":"slides"}"#).unwrap();
+        assert!(matches!(request.command, Command::Inhibit { reason: None, duration: None, .. }));
+    }
+
+    #[test]
+    fn responses() {
+        round_trip(Response { version: PROTOCOL_VERSION, reply: Reply::Ok }, json!({"version": 1, "result": "ok"}));
+        round_trip(Response { version: PROTOCOL_VERSION, reply: Reply::error("nope") },
+            json!({"version": 1, "result": "error", "message": "nope"}));
+    }
+
+    #[test]
+    fn old_status_still_parses() {
+        // before stages, ambient light, power profiles, lid and tablet mode
+        let response: Response = serde_json::from_value(json!({
+            "version": 1, "result": "status", "state": "not-idle", "target": 80, "current": 80, "levels": [80],
+            "fading": false, "timeout": 5.0, "fade-in": 0.2, "fade-out": 1.0,
+        })).unwrap();
+        let Reply::Status(status) = response.reply else {
+            panic!("expected a status, got {:?}", response.reply);
+        };
+        assert_eq!((status.target, status.backlight, status.stage), (80, 0, 0));
+        assert!(status.stages.is_empty() && status.power.is_none() && !status.lid_closed);
+    }
+
+    #[test]
+    fn events() {
+        let event = |event| EventMessage { version: PROTOCOL_VERSION, event };
+        round_trip(event(Notification::State { state: State::Idle }), json!({"version": 1, "event": "state", "state": "idle"}));
+        round_trip(event(Notification::FadeStarted { from: 80, to: 0 }), json!({"version": 1, "event": "fade-started", "from": 80, "to": 0}));
+        round_trip(event(Notification::FadeFinished { level: 0 }), json!({"version": 1, "event": "fade-finished", "level": 0}));
+        round_trip(event(Notification::Brightness { level: 40, source: Source::Uleds }),
+            json!({"version": 1, "event": "brightness", "level": 40, "source": "uleds"}));
+    }
+
+    #[test]
+    fn looks_up_groups() {
+        let groups = "root:x:0:\nwheel:x:10:alice,bob\nvideo:x:39:\n";
+        assert_eq!(find_group(groups, "wheel"), Some(10));
+        assert_eq!(find_group(groups, "video"), Some(39));
+        assert_eq!(find_group(groups, "whee"), None);
+        assert_eq!(group_id("1234").unwrap(), 1234);
+    }
+}
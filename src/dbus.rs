@@ -0,0 +1,142 @@
+//! UPower compatible keyboard backlight D-Bus interface
+//!
+//! GNOME and KDE's keyboard backlight sliders and OSDs talk to `org.freedesktop.UPower.KbdBacklight`.
+//! This serves it, mapped onto the user's brightness, same as the uleds device, so the sliders
+//! don't fight us over the hardware level while we're fading.
+//!
+//! https://upower.freedesktop.org/docs/KbdBacklight.html
+use anyhow::Result;
+use log::info;
+use zbus::object_server::SignalEmitter;
+use zbus::{connection, fdo, interface, Connection};
+
+use crate::control::{Command, Reply, Requester};
+
+pub const DEFAULT_NAME: &str = "org.freedesktop.UPower";
+const PATH: &str = "/org/freedesktop/UPower/KbdBacklight";
+
+struct KbdBacklight {
+    requester: Requester,
+}
+
+impl KbdBacklight {
+    async fn request(&self, command: Command) -> fdo::Result<Reply> {
+        match self.requester.request(command).await {
+            Ok(Reply::Error { message }) => Err(fdo::Error::Failed(message)),
+            Ok(reply) => Ok(reply),
+            Err(e) => Err(fdo::Error::Failed(e.to_string())),
+        }
+    }
+}
+
+#[interface(name = "org.freedesktop.UPower.KbdBacklight")]
+impl KbdBacklight {
+    async fn get_max_brightness(&self) -> i32 {
+        100
+    }
+
+    async fn get_brightness(&self) -> fdo::Result<i32> {
+        match self.request(Command::Status).await? {
+            Reply::Status(status) => Ok(status.target as i32),
+            reply => Err(fdo::Error::Failed(format!("unexpected reply {reply:?}"))),
+        }
+    }
+
+    async fn set_brightness(&self, value: i32) -> fdo::Result<()> {
+        let level = u8::try_from(value).ok().filter(|&level| level <= 100)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("brightness has to be 0-100, not {value}")))?;
+        self.request(Command::SetBrightness { level }).await?;
+        Ok(())
+    }
+
+    #[zbus(signal)]
+    async fn brightness_changed(emitter: &SignalEmitter<'_>, value: i32) -> zbus::Result<()>;
+}
+
+pub struct Dbus {
+    connection: Connection,
+}
+
+impl Dbus {
+    /// Serve the interface on the system bus as `name`
+    ///
+    /// The bus can be overridden with `DBUS_SYSTEM_BUS_ADDRESS`, e.x. for testing with a private dbus-daemon.
+    pub async fn new(name: &str, requester: Requester) -> Result<Self> {
+        Self::serve(connection::Builder::system()?, name, requester).await
+    }
+
+    async fn serve<'a>(builder: connection::Builder<'a>, name: &'a str, requester: Requester) -> Result<Self> {
+        let connection = builder
+            .name(name)?
+            .serve_at(PATH, KbdBacklight { requester })?
+            .build()
+            .await?;
+        info!("serving {PATH} on the bus as {name}");
+        Ok(Dbus { connection })
+    }
+
+    /// Let listeners know the user's brightness changed
+    pub async fn brightness_changed(&self, level: u8) -> Result<()> {
+        let emitter = SignalEmitter::new(&self.connection, PATH)?;
+        KbdBacklight::brightness_changed(&emitter, level as i32).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{Requests, Source};
+    use crate::test_util::TestBus;
+    use tokio::sync::broadcast;
+    use zbus::proxy;
+
+    const NAME: &str = "org.example.fwkbd";
+
+    #[proxy(interface = "org.freedesktop.UPower.KbdBacklight")]
+    trait Client {
+        fn get_max_brightness(&self) -> zbus::Result<i32>;
+        fn get_brightness(&self) -> zbus::Result<i32>;
+        fn set_brightness(&self, value: i32) -> zbus::Result<()>;
+    }
+
+    /// Stands in for the main loop, keeping track of the brightness
+    async fn answer(mut requests: Requests) {
+        let mut target = 80;
+        loop {
+            let pending = requests.next().await;
+            assert_eq!(pending.source, Source::Dbus);
+            let reply = match pending.command {
+                Command::Status => Reply::Status(serde_json::from_value(serde_json::json!({
+                    "state": "not-idle", "target": target, "current": target, "levels": [target],
+                    "fading": false, "timeout": 5.0, "fade-in": 0.2, "fade-out": 1.0,
+                })).unwrap()),
+                Command::SetBrightness { level } => {
+                    target = level;
+                    Reply::Ok
+                },
+                ref command => Reply::error(format!("unexpected {command:?}")),
+            };
+            pending.reply(reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_and_set_brightness() {
+        let Some(bus) = TestBus::start() else { return; };
+        let requests = Requests::new(broadcast::channel(8).0);
+        let requester = requests.requester(Source::Dbus);
+        tokio::spawn(answer(requests));
+        let _dbus = Dbus::serve(connection::Builder::address(bus.address.as_str()).unwrap(), NAME, requester).await.unwrap();
+
+        let connection = connection::Builder::address(bus.address.as_str()).unwrap().build().await.unwrap();
+        let client = ClientProxy::builder(&connection).destination(NAME).unwrap().path(PATH).unwrap().build().await.unwrap();
+        assert_eq!(client.get_max_brightness().await.unwrap(), 100);
+        assert_eq!(client.get_brightness().await.unwrap(), 80);
+        client.set_brightness(40).await.unwrap();
+        assert_eq!(client.get_brightness().await.unwrap(), 40);
+        assert!(client.set_brightness(101).await.is_err());
+        assert!(client.set_brightness(-1).await.is_err());
+        assert_eq!(client.get_brightness().await.unwrap(), 40);
+    }
+}
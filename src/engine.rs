@@ -0,0 +1,1286 @@
+//! The idle/fade state machine
+//!
+//! This doesn't know about tokio, libinput, uleds or any backend. It's fed [`Event`]s and the
+//! current [`Time`], and hands back the levels each channel should be set to, so all of the
+//! timing logic can be tested with a virtual clock.
+use keyframe::ease_with_scaled_time;
+use log::{debug, info, trace};
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+use crate::cli::{self, AlsMode, KeyframeFunction, TabletPolicy};
+
+/// Time as far as the engine is concerned, i.e. time since some fixed starting point
+pub type Time = Duration;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    Idle,
+    NotIdle
+}
+
+/// Where activity came from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Input {
+    Key,
+    /// Pointer motion, clicks, scrolling and gestures
+    Pointer,
+    /// Anything else, e.x. a touchscreen, or `fwkbd wake`
+    Other,
+}
+
+/// Something that happened that the engine should know about
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// The user touched the keyboard or pointer
+    Activity(Input),
+    /// The user picked a new brightness (0-100), e.x. through uleds
+    Brightness(u8),
+    /// The first channel was found at this (scaled) level, when we didn't set it there
+    External(u8),
+    /// Go idle now, without waiting for the timeout
+    Idle,
+    /// The ambient light sensor says the room is (or isn't) bright enough to do without the backlight
+    RoomBright(bool),
+    /// The ambient light curve's level (0-100) for the room, `None` if we aren't following one
+    Ambient(Option<u8>),
+    /// Keep the backlight at or under this (0-100), e.x. on low battery
+    Cap(Option<u8>),
+    /// The lid was closed (`true`) or opened (`false`)
+    Lid(bool),
+    /// Folded into (`true`) or out of tablet mode
+    TabletMode(bool),
+    /// About to suspend (`true`) or just resumed (`false`)
+    Sleep(bool),
+}
+
+/// Something the engine did that outsiders might want to know about
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Notice {
+    State(State),
+    /// Fading the (unscaled) backlight from one level to another
+    FadeStarted { from: u8, to: u8 },
+    FadeFinished { level: u8 },
+}
+
+/// One step of going idle, e.x. dim to 30% after 10 seconds
+#[derive(Clone, Debug)]
+pub struct Stage {
+    /// Time since the last activity
+    pub timeout: Duration,
+    /// Percentage of the user's brightness to fade to
+    pub level: u8,
+    /// Time to fade to `level`
+    pub fade_out: Duration,
+    pub ease_out: KeyframeFunction,
+}
+
+impl Stage {
+    /// Where this stage puts the (unscaled) backlight
+    fn target(&self, backlight: u8) -> u8 {
+        (backlight as u32 * self.level as u32 / 100) as u8
+    }
+}
+
+/// Timing settings
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Idle stages, in order of timeout
+    pub stages: Vec<Stage>,
+    /// Time to fade in the keyboard
+    pub fade_in: Duration,
+    pub ease_in: KeyframeFunction,
+    /// Minimum time between level changes during a fade
+    pub tween_spacing: Duration,
+    /// How the user's brightness combines with the ambient light level
+    pub als_mode: AlsMode,
+    /// Cap on the backlight, e.x. lower on battery
+    pub max_brightness: u8,
+    /// What to do while in tablet mode
+    pub tablet_policy: TabletPolicy,
+}
+
+impl Config {
+    /// These settings, with a power profile's on top
+    pub fn with_profile(&self, profile: &cli::ProfileSpec) -> Config {
+        let mut config = self.clone();
+        if let (Some(timeout), Some(first)) = (profile.timeout, self.stages.first()) {
+            let first = first.timeout.as_secs_f32();
+            for stage in &mut config.stages {
+                let scaled = if first > 0.0 {
+                    // keep the stages in proportion, e.x. dimming at half the timeout
+                    stage.timeout.as_secs_f32() * (timeout / first)
+                } else {
+                    stage.timeout.as_secs_f32() + timeout
+                };
+                stage.timeout = Duration::from_secs_f32(scaled.min(cli::MAX_SECONDS));
+            }
+        }
+        if let Some(max) = profile.max_brightness {
+            config.max_brightness = max;
+        }
+        config
+    }
+}
+
+impl From<&cli::Args> for Config {
+    fn from(args: &cli::Args) -> Self {
+        let mut stages: Vec<Stage> = args.stage_specs().iter().map(|spec| Stage {
+            timeout: Duration::from_secs_f32(spec.timeout),
+            level: spec.level,
+            fade_out: Duration::from_secs_f32(spec.fade_out.unwrap_or(args.fade_out)),
+            ease_out: spec.ease_out.unwrap_or(args.ease_out),
+        }).collect();
+        stages.sort_by_key(|stage| stage.timeout);
+        Config {
+            stages,
+            fade_in: Duration::from_secs_f32(args.fade_in),
+            ease_in: args.ease_in,
+            tween_spacing: Duration::from_millis(50),
+            als_mode: args.als_mode,
+            max_brightness: 100,
+            tablet_policy: args.tablet_mode,
+        }
+    }
+}
+
+/// How the user's brightness maps onto one output
+#[derive(Clone, Debug)]
+pub struct Channel {
+    /// Multiplier applied to the brightness
+    pub scale: f32,
+    /// Lowest level while lit
+    pub min: u8,
+    pub ease_in: Option<KeyframeFunction>,
+    pub ease_out: Option<KeyframeFunction>,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel {
+            scale: 1.0,
+            min: 0,
+            ease_in: None,
+            ease_out: None,
+        }
+    }
+}
+
+impl From<&cli::SinkSpec> for Channel {
+    fn from(spec: &cli::SinkSpec) -> Self {
+        Channel {
+            scale: spec.scale,
+            min: spec.min,
+            ease_in: spec.ease_in,
+            ease_out: spec.ease_out,
+        }
+    }
+}
+
+impl Channel {
+    /// Map the user's brightness (0-100) onto this channel, keeping it at least `min` unless it's off
+    pub fn scaled(&self, level: u8) -> u8 {
+        if level == 0 {
+            return 0;
+        }
+        ((level as f32 * self.scale).round().min(100.0) as u8).max(self.min)
+    }
+
+    /// Map a level read from this channel back to the user's brightness
+    pub fn unscaled(&self, level: u8) -> u8 {
+        if self.scale == 0.0 {
+            return level;
+        }
+        (level as f32 / self.scale).round().min(100.0) as u8
+    }
+}
+
+/// Something keeping the keyboard lit, e.x. a presentation
+#[derive(Clone, Debug)]
+pub struct Inhibitor {
+    pub name: String,
+    pub reason: String,
+    /// How many times it's been taken and not released yet
+    pub count: u32,
+    /// When it goes away on its own, if ever
+    pub expires: Option<Time>,
+}
+
+/// A fade in progress
+#[derive(Clone, Debug)]
+struct Fade {
+    start: Time,
+    duration: Duration,
+    /// Unscaled brightness we're fading from/to, for bookkeeping
+    from: f32,
+    to: u8,
+    func: KeyframeFunction,
+    /// Per channel levels and curves
+    channel_from: Vec<f32>,
+    channel_to: Vec<u8>,
+    channel_funcs: Vec<KeyframeFunction>,
+}
+
+#[derive(Debug)]
+pub struct Engine {
+    config: Config,
+    channels: Vec<Channel>,
+    state: State,
+    /// The user's brightness setting, e.x. from uleds
+    brightness: u8,
+    /// What the keyboard should be at while lit, i.e. the user's brightness adjusted for the room
+    backlight: u8,
+    /// The ambient light curve's level, if we're following one
+    ambient: Option<u8>,
+    /// On top of [`Config::max_brightness`]
+    cap: Option<u8>,
+    /// The current backlight setting, i.e. what we last asked for (before per-channel scaling)
+    current: u8,
+    /// What we last asked each channel to be set to
+    levels: Vec<u8>,
+    last_activity: Time,
+    /// How many idle stages we've gone through, 0 while not idle
+    stage: usize,
+    fade: Option<Fade>,
+    /// While there are any of these, we don't go idle
+    inhibitors: Vec<Inhibitor>,
+    /// Waiting for [`Engine::take_notices`]
+    notices: Vec<Notice>,
+    /// Keep the backlight off, no matter the state
+    room_bright: bool,
+    /// Keep the backlight off and ignore activity, e.x. docked with an external keyboard
+    lid_closed: bool,
+    /// Folded into tablet mode, see [`Config::tablet_policy`]
+    tablet_mode: bool,
+    /// Suspending, so the backlight goes straight off
+    sleeping: bool,
+}
+
+impl Engine {
+    /// Start out not idle, assuming every channel is already at `brightness`
+    pub fn new(config: Config, channels: Vec<Channel>, brightness: u8, now: Time) -> Self {
+        let levels = channels.iter().map(|c| c.scaled(brightness)).collect();
+        Engine {
+            config,
+            channels,
+            state: State::NotIdle,
+            brightness,
+            backlight: brightness,
+            ambient: None,
+            cap: None,
+            current: brightness,
+            levels,
+            last_activity: now,
+            stage: 0,
+            fade: None,
+            inhibitors: vec![],
+            notices: vec![],
+            room_bright: false,
+            lid_closed: false,
+            tablet_mode: false,
+            sleeping: false,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// How many idle stages we've gone through, 0 while not idle
+    pub fn stage(&self) -> usize {
+        self.stage
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    /// What we fade in to, which is [`Engine::brightness`] unless the room says otherwise
+    pub fn backlight(&self) -> u8 {
+        self.backlight
+    }
+
+    /// Where the (unscaled) backlight is right now, which lags [`Engine::backlight`] during a fade
+    pub fn current(&self) -> u8 {
+        self.current
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    /// Everything that's happened since the last call
+    pub fn take_notices(&mut self) -> Vec<Notice> {
+        std::mem::take(&mut self.notices)
+    }
+
+    pub fn inhibitors(&self) -> &[Inhibitor] {
+        &self.inhibitors
+    }
+
+    pub fn is_inhibited(&self) -> bool {
+        !self.inhibitors.is_empty()
+    }
+
+    pub fn is_room_bright(&self) -> bool {
+        self.room_bright
+    }
+
+    pub fn cap(&self) -> Option<u8> {
+        self.cap
+    }
+
+    pub fn is_lid_closed(&self) -> bool {
+        self.lid_closed
+    }
+
+    pub fn is_tablet_mode(&self) -> bool {
+        self.tablet_mode
+    }
+
+    /// Keep the keyboard lit until `name` is released as many times as it's been taken, or
+    /// `duration` runs out
+    ///
+    /// Taking an inhibitor counts as activity, so it also wakes the keyboard up. Taking it again
+    /// without a reason keeps the old one.
+    pub fn inhibit(&mut self, name: &str, reason: Option<&str>, duration: Option<Duration>, now: Time) {
+        // one that outlasts the clock never expires
+        let expires = duration.and_then(|duration| now.checked_add(duration));
+        match self.inhibitors.iter_mut().find(|inhibitor| inhibitor.name == name) {
+            Some(inhibitor) => {
+                inhibitor.count += 1;
+                if let Some(reason) = reason {
+                    inhibitor.reason = reason.to_string();
+                }
+                // whichever lasts longer
+                inhibitor.expires = inhibitor.expires.zip(expires).map(|(a, b)| a.max(b));
+            },
+            None => {
+                let reason = reason.unwrap_or("no reason given");
+                info!("inhibited by {name}: {reason}");
+                self.inhibitors.push(Inhibitor {
+                    name: name.to_string(),
+                    reason: reason.to_string(),
+                    count: 1,
+                    expires,
+                });
+            },
+        }
+        self.handle(Event::Activity(Input::Other), now);
+    }
+
+    /// Release `name` once, returns `false` if it isn't held
+    ///
+    /// The idle timeout starts over from when the last hold is released.
+    pub fn uninhibit(&mut self, name: &str, now: Time) -> bool {
+        let Some(i) = self.inhibitors.iter().position(|inhibitor| inhibitor.name == name) else {
+            return false;
+        };
+        self.inhibitors[i].count -= 1;
+        if self.inhibitors[i].count == 0 {
+            info!("{name} released");
+            self.inhibitors.remove(i);
+            self.last_activity = self.last_activity.max(now);
+        }
+        true
+    }
+
+    /// What we last asked the channels to be at
+    pub fn levels(&self) -> &[u8] {
+        &self.levels
+    }
+
+    pub fn is_fading(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    /// The levels to restore the keyboard to on exit
+    pub fn restore_levels(&self) -> Vec<u8> {
+        self.channels.iter().map(|c| c.scaled(self.brightness)).collect()
+    }
+
+    /// Swap in new settings, keeping the current state and brightness
+    ///
+    /// `channels` is only applied if it has the same number of channels as before.
+    pub fn reconfigure(&mut self, config: Config, channels: Vec<Channel>, now: Time) {
+        self.config = config;
+        self.update_backlight();
+        // stay idle, even if there are fewer stages now
+        if self.state == State::Idle {
+            self.stage = self.stage.min(self.config.stages.len()).max(1);
+        }
+        if channels.len() == self.channels.len() {
+            self.channels = channels;
+        } else {
+            info!("number of sinks changed, that needs a restart");
+        }
+        // in case the channel scaling changed
+        self.start_fade(now);
+    }
+
+    /// Tell the engine where the channels actually are, e.x. read back at startup
+    ///
+    /// Channels with no level are assumed to be in line with the first one. If `adopt` is set,
+    /// the first channel's (nonzero) level becomes the user's brightness.
+    pub fn sync_levels(&mut self, levels: &[Option<u8>], adopt: bool, now: Time) {
+        if let Some(Some(first)) = levels.first() {
+            self.current = self.channels[0].unscaled(*first);
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            self.levels[i] = levels.get(i).copied().flatten().unwrap_or_else(|| channel.scaled(self.current));
+        }
+        // adopting 0 would leave the keyboard dark forever, e.x. if we were killed while idle
+        if adopt && self.current != 0 {
+            self.brightness = self.current;
+            self.update_backlight();
+        }
+        self.start_fade(now);
+    }
+
+    /// Whether activity from `input` counts right now
+    pub fn accepts(&self, input: Input) -> bool {
+        // the lid being closed means e.x. docked with an external keyboard,
+        // and folded into a tablet the keyboard's facing down, so key presses are probably the table
+        !(self.lid_closed || self.sleeping ||
+            (input == Input::Key && self.tablet_mode && self.config.tablet_policy == TabletPolicy::Ignore))
+    }
+
+    pub fn handle(&mut self, event: Event, now: Time) {
+        trace!("handle({event:?})");
+        match event {
+            Event::Activity(input) if !self.accepts(input) => {
+                trace!("ignoring {input:?} activity, the lid is closed, we're folded up or suspending");
+            },
+            Event::Activity(_) => {
+                self.last_activity = now;
+                if self.state == State::Idle {
+                    self.set_state(State::NotIdle);
+                    self.start_fade(now);
+                }
+            },
+            Event::Brightness(level) => {
+                info!("brightness changed to {level}");
+                self.brightness = level;
+                self.update_backlight();
+                self.set_state(State::NotIdle);
+                self.last_activity = now;
+                self.start_fade(now);
+            },
+            Event::External(level) => {
+                info!("backlight changed externally from {} to {level}", self.levels[0]);
+                self.levels[0] = level;
+                self.current = self.channels[0].unscaled(level);
+                self.brightness = self.current;
+                self.update_backlight();
+                self.set_state(State::NotIdle);
+                self.last_activity = now;
+                // bring any other channels along
+                self.start_fade(now);
+            },
+            Event::Idle => {
+                if self.is_inhibited() {
+                    debug!("not going idle, inhibited");
+                } else if self.stage < self.config.stages.len() {
+                    info!("going idle early");
+                    // straight to the last stage
+                    self.stage = self.config.stages.len();
+                    self.set_state(State::Idle);
+                    self.start_fade(now);
+                }
+            },
+            Event::RoomBright(bright) => {
+                if self.room_bright != bright {
+                    info!("room is {}", if bright { "bright, turning the backlight off" } else { "dark again" });
+                    self.room_bright = bright;
+                    self.update_backlight();
+                    self.start_fade(now);
+                }
+            },
+            Event::Ambient(level) => {
+                if self.ambient != level {
+                    debug!("ambient light level is now {level:?}");
+                    self.ambient = level;
+                    self.update_backlight();
+                    self.start_fade(now);
+                }
+            },
+            Event::Lid(closed) => {
+                if self.lid_closed != closed {
+                    info!("lid {}", if closed { "closed" } else { "opened" });
+                    self.lid_closed = closed;
+                    self.update_backlight();
+                    if !closed {
+                        // opening the lid is as good as touching the keyboard
+                        self.last_activity = now;
+                        self.set_state(State::NotIdle);
+                    }
+                    self.start_fade(now);
+                }
+            },
+            Event::TabletMode(folded) => {
+                if self.tablet_mode != folded {
+                    info!("{} tablet mode", if folded { "entered" } else { "left" });
+                    self.tablet_mode = folded;
+                    self.update_backlight();
+                    if !folded {
+                        // unfolding it means someone's about to type
+                        self.last_activity = now;
+                        self.set_state(State::NotIdle);
+                    }
+                    self.start_fade(now);
+                }
+            },
+            Event::Sleep(sleeping) => {
+                if self.sleeping != sleeping {
+                    self.sleeping = sleeping;
+                    self.update_backlight();
+                    if !sleeping {
+                        // whoever woke it up is about to type
+                        self.last_activity = now;
+                        self.set_state(State::NotIdle);
+                    }
+                    self.start_fade(now);
+                }
+            },
+            Event::Cap(cap) => {
+                if self.cap != cap {
+                    info!("brightness cap is now {cap:?}");
+                    self.cap = cap;
+                    self.update_backlight();
+                    self.start_fade(now);
+                }
+            },
+        }
+    }
+
+    /// Advance to `now`
+    ///
+    /// Returns the new per-channel levels if any of them changed.
+    pub fn tick(&mut self, now: Time) -> Option<Vec<u8>> {
+        let mut expired = vec![];
+        self.inhibitors.retain(|inhibitor| match inhibitor.expires {
+            Some(expires) if expires <= now => {
+                expired.push((inhibitor.name.clone(), expires));
+                false
+            },
+            _ => true,
+        });
+        for (name, expires) in expired {
+            info!("{name} expired");
+            self.last_activity = self.last_activity.max(expires);
+        }
+
+        if !self.is_inhibited() {
+            let reached = self.config.stages.iter()
+                .take_while(|stage| now >= self.last_activity + stage.timeout)
+                .count();
+            if reached > self.stage {
+                info!("going idle, stage {reached}/{}", self.config.stages.len());
+                self.stage = reached;
+                self.set_state(State::Idle);
+                self.start_fade(now);
+            }
+        }
+
+        let fade = self.fade.as_ref()?;
+        let elapsed = now.saturating_sub(fade.start);
+        let levels = if elapsed >= fade.duration {
+            self.current = fade.to;
+            let levels = fade.channel_to.clone();
+            self.fade = None;
+            self.notices.push(Notice::FadeFinished { level: self.current });
+            levels
+        } else {
+            let ease = |func, from, to: u8| {
+                ease_with_scaled_time(func, from, to as f32, elapsed.as_secs_f32(), fade.duration.as_secs_f32()) as u8
+            };
+            self.current = ease(fade.func, fade.from, fade.to);
+            fade.channel_funcs.iter().zip(&fade.channel_from).zip(&fade.channel_to)
+                .map(|((&func, &from), &to)| ease(func, from, to))
+                .collect()
+        };
+
+        if levels == self.levels {
+            debug!("tweened too fast");
+            return None;
+        }
+        debug!("levels={levels:?}, elapsed={elapsed:?}");
+        self.levels.clone_from(&levels);
+        Some(levels)
+    }
+
+    /// When [`Engine::tick`] next needs to be called, if nothing else happens first
+    pub fn next_deadline(&self, now: Time) -> Option<Time> {
+        let fade_deadline = self.fade.as_ref()
+            .map(|fade| (now + self.config.tween_spacing).min(fade.start + fade.duration));
+        let idle_deadline = if self.is_inhibited() {
+            // an inhibitor expiring gets the idle timer going again
+            self.inhibitors.iter()
+                .filter_map(|inhibitor| inhibitor.expires)
+                .min()
+        } else {
+            // too far off to ever happen is the same as never
+            self.config.stages.get(self.stage).and_then(|stage| self.last_activity.checked_add(stage.timeout))
+        };
+        match (fade_deadline, idle_deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Work out what the keyboard should be at while lit, from the user's brightness and the room
+    fn update_backlight(&mut self) {
+        let backlight = match self.ambient {
+            // off is off, whatever the room's like
+            _ if self.room_bright || self.lid_closed || self.sleeping || self.brightness == 0 => 0,
+            _ if self.tablet_mode && self.config.tablet_policy == TabletPolicy::Off => 0,
+            None => self.brightness,
+            Some(level) => match self.config.als_mode {
+                AlsMode::Ceiling => level.min(self.brightness),
+                AlsMode::Offset => (level as i16 + self.brightness as i16 - 50).clamp(0, 100) as u8,
+            },
+        };
+        self.backlight = backlight.min(self.config.max_brightness).min(self.cap.unwrap_or(100));
+    }
+
+    /// Switch states, letting subscribers know if it's a change
+    fn set_state(&mut self, state: State) {
+        if state == State::NotIdle {
+            self.stage = 0;
+        }
+        if self.state != state {
+            self.state = state;
+            self.notices.push(Notice::State(state));
+        }
+    }
+
+    /// Start fading towards the right level for the current state, if we aren't already there
+    fn start_fade(&mut self, now: Time) {
+        let stage = self.stage.checked_sub(1).and_then(|i| self.config.stages.get(i));
+        let (to, duration, func) = match (self.state, stage) {
+            (State::Idle, Some(stage)) => (stage.target(self.backlight), stage.fade_out, stage.ease_out),
+            // no stages at all, just turn off
+            (State::Idle, None) => (0, Duration::ZERO, self.config.ease_in),
+            (State::NotIdle, _) => (self.backlight, self.config.fade_in, self.config.ease_in),
+        };
+        // nobody's watching it fade
+        let duration = if self.lid_closed || self.sleeping { Duration::ZERO } else { duration };
+        let channel_to: Vec<u8> = self.channels.iter().map(|c| c.scaled(to)).collect();
+        if self.current == to && self.levels == channel_to {
+            if self.fade.take().is_some() {
+                // the fade we were in the middle of already got us here
+                self.notices.push(Notice::FadeFinished { level: to });
+            }
+            return;
+        }
+        trace!("start_fade(to={to})");
+        self.notices.push(Notice::FadeStarted { from: self.current, to });
+        let channel_funcs = self.channels.iter().zip(&self.levels).zip(&channel_to)
+            .map(|((channel, &from), &to)| {
+                let curve = if to < from { channel.ease_out } else { channel.ease_in };
+                curve.unwrap_or(func)
+            })
+            .collect();
+        self.fade = Some(Fade {
+            start: now,
+            duration,
+            from: self.current as f32,
+            to,
+            func,
+            channel_from: self.levels.iter().map(|&l| l as f32).collect(),
+            channel_to,
+            channel_funcs,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const FADE_IN: Duration = Duration::from_millis(200);
+    const FADE_OUT: Duration = Duration::from_secs(1);
+
+    fn stage(timeout: Time, level: u8) -> Stage {
+        Stage {
+            timeout,
+            level,
+            fade_out: FADE_OUT,
+            ease_out: KeyframeFunction::Linear,
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            stages: vec![stage(TIMEOUT, 0)],
+            fade_in: FADE_IN,
+            ease_in: KeyframeFunction::Linear,
+            tween_spacing: Duration::from_millis(50),
+            als_mode: AlsMode::Ceiling,
+            max_brightness: 100,
+            tablet_policy: TabletPolicy::Off,
+        }
+    }
+
+    fn ms(ms: u64) -> Time {
+        Duration::from_millis(ms)
+    }
+
+    /// An engine with one plain channel, started at t=0
+    fn engine(backlight: u8) -> Engine {
+        Engine::new(config(), vec![Channel::default()], backlight, ms(0))
+    }
+
+    /// Tick every `step` from `from` to `to` inclusive, collecting what would be sent to the hardware
+    fn run(engine: &mut Engine, from: Time, to: Time, step: Duration) -> Vec<(Time, Vec<u8>)> {
+        let mut out = vec![];
+        let mut now = from;
+        while now <= to {
+            if let Some(levels) = engine.tick(now) {
+                out.push((now, levels));
+            }
+            now += step;
+        }
+        out
+    }
+
+    #[test]
+    fn stays_lit_before_timeout() {
+        let mut engine = engine(80);
+        assert!(run(&mut engine, ms(0), TIMEOUT - ms(1), ms(10)).is_empty());
+        assert_eq!(engine.state(), State::NotIdle);
+        assert_eq!(engine.next_deadline(ms(0)), Some(TIMEOUT));
+    }
+
+    #[test]
+    fn idle_timeout_fades_out() {
+        let mut engine = engine(80);
+        let out = run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        assert_eq!(engine.state(), State::Idle);
+        assert_eq!(out.last().unwrap(), &(TIMEOUT + FADE_OUT, vec![0]));
+        // linear fade, so the levels only ever go down
+        assert!(out.windows(2).all(|w| w[0].1[0] > w[1].1[0]));
+        assert!(!engine.is_fading());
+        assert_eq!(engine.next_deadline(TIMEOUT + FADE_OUT), None);
+    }
+
+    #[test]
+    fn activity_resets_idle_timer() {
+        let mut engine = engine(80);
+        engine.handle(Event::Activity(Input::Key), ms(4000));
+        assert!(run(&mut engine, ms(0), ms(8999), ms(100)).is_empty());
+        assert_eq!(engine.next_deadline(ms(4000)), Some(ms(9000)));
+        engine.tick(ms(9000));
+        assert_eq!(engine.state(), State::Idle);
+        assert!(engine.tick(ms(9050)).is_some());
+    }
+
+    #[test]
+    fn activity_while_idle_fades_back_in() {
+        let mut engine = engine(80);
+        run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        let wake = ms(20_000);
+        engine.handle(Event::Activity(Input::Key), wake);
+        assert_eq!(engine.state(), State::NotIdle);
+        let out = run(&mut engine, wake, wake + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap(), &(wake + FADE_IN, vec![80]));
+        assert!(out.windows(2).all(|w| w[0].1[0] < w[1].1[0]));
+    }
+
+    #[test]
+    fn interrupted_fade_out_fades_in_from_where_it_was() {
+        let mut engine = engine(100);
+        let half = TIMEOUT + FADE_OUT / 2;
+        let out = run(&mut engine, TIMEOUT, half, ms(50));
+        let dimmed = out.last().unwrap().1[0];
+        assert!(dimmed > 0 && dimmed < 100, "dimmed={dimmed}");
+
+        engine.handle(Event::Activity(Input::Key), half);
+        assert_eq!(engine.state(), State::NotIdle);
+        let out = run(&mut engine, half + ms(50), half + FADE_IN, ms(50));
+        // picks up from the dimmed level rather than jumping to 0 or 100
+        assert!(out.first().unwrap().1[0] > dimmed);
+        assert_eq!(out.last().unwrap().1, vec![100]);
+    }
+
+    #[test]
+    fn inhibitor_keeps_keyboard_lit() {
+        let mut engine = engine(80);
+        engine.inhibit("slides", Some("presenting"), None, ms(0));
+        assert!(run(&mut engine, ms(0), TIMEOUT * 4, ms(100)).is_empty());
+        assert_eq!(engine.state(), State::NotIdle);
+        assert_eq!(engine.next_deadline(TIMEOUT * 4), None);
+        // forcing idle doesn't get around it either
+        engine.handle(Event::Idle, TIMEOUT * 4);
+        assert_eq!(engine.state(), State::NotIdle);
+
+        let release = ms(30_000);
+        assert!(engine.uninhibit("slides", release));
+        assert_eq!(engine.next_deadline(release), Some(release + TIMEOUT));
+    }
+
+    #[test]
+    fn endless_inhibitor_never_expires() {
+        let mut engine = engine(80);
+        engine.inhibit("forever", None, Some(Duration::MAX), ms(1000));
+        assert_eq!(engine.inhibitors()[0].expires, None);
+        assert_eq!(engine.next_deadline(ms(1000) + FADE_IN), None);
+        assert!(engine.is_inhibited());
+    }
+
+    #[test]
+    fn inhibitors_are_refcounted() {
+        let mut engine = engine(80);
+        engine.inhibit("slides", Some("presenting"), None, ms(0));
+        engine.inhibit("slides", None, None, ms(0));
+        assert_eq!(engine.inhibitors()[0].count, 2);
+        assert_eq!(engine.inhibitors()[0].reason, "presenting");
+        assert!(engine.uninhibit("slides", ms(0)));
+        assert!(engine.is_inhibited());
+        assert!(engine.uninhibit("slides", ms(0)));
+        assert!(!engine.is_inhibited());
+        assert!(!engine.uninhibit("slides", ms(0)));
+    }
+
+    #[test]
+    fn inhibitor_expires() {
+        let mut engine = engine(80);
+        let expires = ms(10_000);
+        engine.inhibit("notes", Some("reading"), Some(expires), ms(0));
+        assert_eq!(engine.next_deadline(ms(0)), Some(expires));
+        assert_eq!(engine.tick(expires), None);
+        assert!(!engine.is_inhibited());
+        assert_eq!(engine.state(), State::NotIdle);
+        assert_eq!(engine.next_deadline(expires), Some(expires + TIMEOUT));
+    }
+
+    #[test]
+    fn inhibiting_while_idle_wakes() {
+        let mut engine = engine(80);
+        run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        let wake = ms(20_000);
+        engine.inhibit("slides", None, None, wake);
+        assert_eq!(engine.state(), State::NotIdle);
+        let out = run(&mut engine, wake, wake + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![80]);
+    }
+
+    #[test]
+    fn notices_follow_idle_cycle() {
+        let mut engine = engine(80);
+        run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        assert_eq!(engine.take_notices(), vec![
+            Notice::State(State::Idle),
+            Notice::FadeStarted { from: 80, to: 0 },
+            Notice::FadeFinished { level: 0 },
+        ]);
+        assert!(engine.take_notices().is_empty());
+
+        let wake = ms(20_000);
+        engine.handle(Event::Activity(Input::Key), wake);
+        engine.handle(Event::Activity(Input::Key), wake + ms(10));
+        run(&mut engine, wake, wake + FADE_IN, ms(50));
+        assert_eq!(engine.take_notices(), vec![
+            Notice::State(State::NotIdle),
+            Notice::FadeStarted { from: 0, to: 80 },
+            Notice::FadeFinished { level: 80 },
+        ]);
+    }
+
+    /// Dim to 30% at the usual timeout, then turn off at 20s
+    fn staged_engine(backlight: u8) -> Engine {
+        let config = Config { stages: vec![stage(TIMEOUT, 30), stage(ms(20_000), 0)], ..config() };
+        Engine::new(config, vec![Channel::default()], backlight, ms(0))
+    }
+
+    #[test]
+    fn stages_dim_then_turn_off() {
+        let mut engine = staged_engine(80);
+        let out = run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![24]);
+        assert_eq!(engine.state(), State::Idle);
+        assert_eq!(engine.stage(), 1);
+        assert_eq!(engine.next_deadline(TIMEOUT + FADE_OUT), Some(ms(20_000)));
+
+        assert_eq!(engine.current(), 24);
+        let out = run(&mut engine, ms(20_000), ms(20_000) + FADE_OUT, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![0]);
+        assert_eq!(engine.stage(), 2);
+        assert_eq!(engine.next_deadline(ms(21_000)), None);
+    }
+
+    #[test]
+    fn activity_wakes_from_any_stage() {
+        let mut engine = staged_engine(80);
+        run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        let wake = ms(10_000);
+        engine.handle(Event::Activity(Input::Key), wake);
+        assert_eq!(engine.state(), State::NotIdle);
+        assert_eq!(engine.stage(), 0);
+        assert_eq!(engine.current(), 24);
+        let out = run(&mut engine, wake, wake + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![80]);
+        // and the stages start over
+        assert_eq!(engine.next_deadline(wake + FADE_IN), Some(wake + TIMEOUT));
+    }
+
+    #[test]
+    fn overdue_stages_are_skipped() {
+        let mut engine = staged_engine(80);
+        // e.x. we were suspended through both timeouts
+        let late = ms(25_000);
+        engine.tick(late);
+        assert_eq!(engine.stage(), 2);
+        let out = run(&mut engine, late, late + FADE_OUT, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![0]);
+    }
+
+    #[test]
+    fn bright_room_keeps_backlight_off() {
+        let mut engine = engine(80);
+        engine.handle(Event::RoomBright(true), ms(0));
+        let out = run(&mut engine, ms(0), FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![0]);
+        assert_eq!(engine.state(), State::NotIdle);
+
+        // typing doesn't bring it back
+        engine.handle(Event::Activity(Input::Key), ms(1000));
+        assert_eq!(engine.tick(ms(1000)), None);
+        assert!(!engine.is_fading());
+
+        // but the room getting dark does, at the user's brightness
+        engine.handle(Event::RoomBright(false), ms(2000));
+        let out = run(&mut engine, ms(2000), ms(2000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![80]);
+        assert_eq!(engine.backlight(), 80);
+    }
+
+    #[test]
+    fn ambient_level_is_capped_by_brightness() {
+        let mut engine = engine(60);
+        // a dark room wants it brighter than the user does
+        engine.handle(Event::Ambient(Some(100)), ms(0));
+        assert_eq!(engine.backlight(), 60);
+        assert!(!engine.is_fading());
+
+        // a lit office wants it fainter
+        engine.handle(Event::Ambient(Some(20)), ms(0));
+        let out = run(&mut engine, ms(0), FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![20]);
+        assert_eq!(engine.brightness(), 60);
+
+        // turning the brightness down below the curve wins
+        engine.handle(Event::Brightness(10), ms(1000));
+        assert_eq!(engine.backlight(), 10);
+
+        // and not following the curve goes back to the user's brightness
+        engine.handle(Event::Ambient(None), ms(1000));
+        assert_eq!(engine.backlight(), 10);
+        engine.handle(Event::Brightness(60), ms(1000));
+        assert_eq!(engine.backlight(), 60);
+    }
+
+    #[test]
+    fn ambient_level_offset_by_brightness() {
+        let config = Config { als_mode: AlsMode::Offset, ..config() };
+        let mut engine = Engine::new(config, vec![Channel::default()], 50, ms(0));
+        engine.handle(Event::Ambient(Some(40)), ms(0));
+        assert_eq!(engine.backlight(), 40);
+        engine.handle(Event::Brightness(70), ms(0));
+        assert_eq!(engine.backlight(), 60);
+        engine.handle(Event::Brightness(5), ms(0));
+        assert_eq!(engine.backlight(), 0);
+        // the idle stages dim from the adjusted level
+        engine.handle(Event::Brightness(80), ms(0));
+        run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        assert_eq!(engine.levels(), &[0]);
+        engine.handle(Event::Activity(Input::Key), ms(10_000));
+        let out = run(&mut engine, ms(10_000), ms(10_000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![70]);
+        // restoring on exit goes back to what the user picked
+        assert_eq!(engine.restore_levels(), vec![80]);
+    }
+
+    #[test]
+    fn profile_switch_fades_to_new_cap() {
+        let mut engine = engine(80);
+        let battery = cli::ProfileSpec { timeout: Some(2.0), max_brightness: Some(50) };
+        engine.reconfigure(config().with_profile(&battery), vec![Channel::default()], ms(0));
+        assert_eq!(engine.backlight(), 50);
+        assert_eq!(engine.brightness(), 80);
+        let out = run(&mut engine, ms(0), FADE_IN, ms(50));
+        assert!(out.len() > 1, "should fade, not jump");
+        assert_eq!(out.last().unwrap().1, vec![50]);
+        assert_eq!(engine.next_deadline(FADE_IN), Some(ms(2000)));
+
+        // and back up when plugged in again
+        engine.reconfigure(config(), vec![Channel::default()], ms(1000));
+        let out = run(&mut engine, ms(1000), ms(1000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![80]);
+    }
+
+    #[test]
+    fn profile_timeout_keeps_stages_in_proportion() {
+        let config = Config { stages: vec![stage(TIMEOUT, 30), stage(ms(20_000), 0)], ..config() };
+        let profile = cli::ProfileSpec { timeout: Some(2.5), max_brightness: None };
+        let timeouts: Vec<_> = config.with_profile(&profile).stages.iter().map(|stage| stage.timeout).collect();
+        assert_eq!(timeouts, vec![ms(2500), ms(10_000)]);
+    }
+
+    #[test]
+    fn profile_timeout_is_capped() {
+        let max = Duration::from_secs_f32(cli::MAX_SECONDS);
+        let config = Config { stages: vec![stage(ms(1), 30), stage(max, 0)], ..config() };
+        let profile = cli::ProfileSpec { timeout: Some(cli::MAX_SECONDS), max_brightness: None };
+        let timeouts: Vec<_> = config.with_profile(&profile).stages.iter().map(|stage| stage.timeout).collect();
+        assert_eq!(timeouts, vec![max, max]);
+
+        let zero = Config { stages: vec![stage(Duration::ZERO, 30), stage(max, 0)], ..self::config() };
+        let timeouts: Vec<_> = zero.with_profile(&profile).stages.iter().map(|stage| stage.timeout).collect();
+        assert_eq!(timeouts, vec![max, max]);
+    }
+
+    #[test]
+    fn unreachable_timeout_has_no_deadline() {
+        let config = Config { stages: vec![stage(Duration::MAX, 0)], ..config() };
+        let mut engine = Engine::new(config, vec![Channel::default()], 80, ms(0));
+        engine.handle(Event::Activity(Input::Key), ms(1000));
+        assert_eq!(engine.next_deadline(ms(1000)), None);
+    }
+
+    #[test]
+    fn cap_holds_brightness_down() {
+        let mut engine = engine(80);
+        engine.handle(Event::Cap(Some(0)), ms(0));
+        let out = run(&mut engine, ms(0), FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![0]);
+
+        // the user's brightness is still theirs, just capped
+        engine.handle(Event::Brightness(60), ms(1000));
+        assert_eq!(engine.brightness(), 60);
+        assert_eq!(engine.backlight(), 0);
+        assert_eq!(engine.restore_levels(), vec![60]);
+
+        engine.handle(Event::Cap(None), ms(2000));
+        let out = run(&mut engine, ms(2000), ms(2000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![60]);
+    }
+
+    #[test]
+    fn closing_lid_turns_off_and_ignores_activity() {
+        let mut engine = engine(80);
+        engine.handle(Event::Lid(true), ms(1000));
+        // straight off, no fade
+        assert_eq!(engine.tick(ms(1000)), Some(vec![0]));
+        assert!(!engine.is_fading());
+
+        // e.x. typing on an external keyboard
+        engine.handle(Event::Activity(Input::Key), ms(2000));
+        engine.handle(Event::Activity(Input::Key), ms(8000));
+        assert_eq!(run(&mut engine, ms(2000), ms(10_000), ms(50)), vec![]);
+        assert_eq!(engine.state(), State::Idle);
+
+        engine.handle(Event::Lid(false), ms(10_000));
+        assert_eq!(engine.state(), State::NotIdle);
+        let out = run(&mut engine, ms(10_000), ms(10_000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![80]);
+        assert_eq!(engine.next_deadline(ms(10_000) + FADE_IN), Some(ms(10_000) + TIMEOUT));
+    }
+
+    #[test]
+    fn tablet_mode_turns_off() {
+        let mut engine = engine(80);
+        engine.handle(Event::TabletMode(true), ms(1000));
+        let out = run(&mut engine, ms(1000), ms(1000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![0]);
+        engine.handle(Event::Activity(Input::Key), ms(2000));
+        assert_eq!(engine.tick(ms(2000)), None);
+
+        engine.handle(Event::TabletMode(false), ms(3000));
+        let out = run(&mut engine, ms(3000), ms(3000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![80]);
+    }
+
+    #[test]
+    fn tablet_mode_ignore_stays_lit() {
+        let config = Config { tablet_policy: TabletPolicy::Ignore, ..config() };
+        let mut engine = Engine::new(config, vec![Channel::default()], 80, ms(0));
+        engine.handle(Event::TabletMode(true), ms(1000));
+        assert!(engine.is_tablet_mode());
+        assert_eq!(engine.backlight(), 80);
+        assert!(!engine.is_fading());
+    }
+
+    #[test]
+    fn tablet_mode_ignore_ignores_keys() {
+        let config = Config { tablet_policy: TabletPolicy::Ignore, ..config() };
+        let mut engine = Engine::new(config, vec![Channel::default()], 80, ms(0));
+        engine.handle(Event::TabletMode(true), ms(1000));
+
+        // key presses don't hold off the timeout
+        engine.handle(Event::Activity(Input::Key), ms(4000));
+        assert_eq!(engine.next_deadline(ms(4000)), Some(TIMEOUT));
+        // but the touchscreen or a mouse do
+        engine.handle(Event::Activity(Input::Pointer), ms(4000));
+        assert_eq!(engine.next_deadline(ms(4000)), Some(ms(4000) + TIMEOUT));
+
+        // and once idle, keys don't wake it
+        run(&mut engine, ms(4000), ms(4000) + TIMEOUT + FADE_OUT, ms(50));
+        assert_eq!(engine.state(), State::Idle);
+        engine.handle(Event::Activity(Input::Key), ms(10_000));
+        assert_eq!(engine.state(), State::Idle);
+        engine.handle(Event::Activity(Input::Other), ms(10_000));
+        assert_eq!(engine.state(), State::NotIdle);
+        run(&mut engine, ms(10_000), ms(10_000) + FADE_IN, ms(50));
+
+        // unfolded, keys count again
+        engine.handle(Event::TabletMode(false), ms(11_000));
+        engine.handle(Event::Activity(Input::Key), ms(12_000));
+        assert_eq!(engine.next_deadline(ms(12_000)), Some(ms(12_000) + TIMEOUT));
+    }
+
+    #[test]
+    fn sleep_turns_off_and_resume_fades_in() {
+        let mut engine = engine(80);
+        engine.handle(Event::Sleep(true), ms(1000));
+        assert_eq!(engine.tick(ms(1000)), Some(vec![0]));
+
+        // the EC came back with its own idea of the level
+        engine.sync_levels(&[Some(30)], false, ms(2000));
+        engine.handle(Event::Sleep(false), ms(2000));
+        assert_eq!(engine.state(), State::NotIdle);
+        assert_eq!(engine.brightness(), 80);
+        let out = run(&mut engine, ms(2000), ms(2000) + FADE_IN, ms(50));
+        assert!(out.first().unwrap().1[0] >= 30);
+        assert_eq!(out.last().unwrap().1, vec![80]);
+    }
+
+    #[test]
+    fn forced_idle_fades_out_until_activity() {
+        let mut engine = engine(80);
+        engine.handle(Event::Idle, ms(1000));
+        assert_eq!(engine.state(), State::Idle);
+        let out = run(&mut engine, ms(1000), ms(1000) + FADE_OUT, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![0]);
+        assert_eq!(engine.next_deadline(ms(1000) + FADE_OUT), None);
+        engine.handle(Event::Activity(Input::Key), ms(3000));
+        assert_eq!(engine.state(), State::NotIdle);
+    }
+
+    #[test]
+    fn activity_during_fade_in_keeps_fading() {
+        let mut engine = engine(100);
+        run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        let wake = ms(20_000);
+        engine.handle(Event::Activity(Input::Key), wake);
+        engine.tick(wake + ms(100));
+        engine.handle(Event::Activity(Input::Key), wake + ms(100));
+        assert!(engine.is_fading());
+        assert_eq!(engine.tick(wake + FADE_IN), Some(vec![100]));
+    }
+
+    #[test]
+    fn brightness_change_fades_to_new_level() {
+        let mut engine = engine(100);
+        engine.handle(Event::Brightness(40), ms(1000));
+        assert_eq!(engine.backlight(), 40);
+        let out = run(&mut engine, ms(1000), ms(1000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![40]);
+        // and the idle timer restarts from the change
+        assert_eq!(engine.next_deadline(ms(1200)), Some(ms(1000) + TIMEOUT));
+    }
+
+    #[test]
+    fn brightness_change_while_idle_wakes() {
+        let mut engine = engine(100);
+        run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT, ms(50));
+        engine.handle(Event::Brightness(60), ms(10_000));
+        assert_eq!(engine.state(), State::NotIdle);
+        let out = run(&mut engine, ms(10_000), ms(10_000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![60]);
+    }
+
+    #[test]
+    fn external_change_is_adopted() {
+        let mut engine = Engine::new(config(), vec![Channel::default(), Channel { scale: 0.5, ..Default::default() }], 100, ms(0));
+        engine.handle(Event::External(30), ms(1000));
+        assert_eq!(engine.backlight(), 30);
+        assert_eq!(engine.levels()[0], 30);
+        // the second channel follows, the first is already there
+        let out = run(&mut engine, ms(1000), ms(1000) + FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![30, 15]);
+    }
+
+    #[test]
+    fn zero_length_fade_jumps() {
+        let mut engine = Engine::new(Config { stages: vec![Stage { fade_out: Duration::ZERO, ..stage(TIMEOUT, 0) }], ..config() }, vec![Channel::default()], 70, ms(0));
+        assert_eq!(engine.tick(TIMEOUT), Some(vec![0]));
+        assert!(!engine.is_fading());
+    }
+
+    #[test]
+    fn sync_levels_fades_from_read_level() {
+        let mut engine = engine(100);
+        engine.sync_levels(&[Some(20)], false, ms(0));
+        assert_eq!(engine.levels(), &[20]);
+        let out = run(&mut engine, ms(0), FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![100]);
+    }
+
+    #[test]
+    fn sync_levels_adopts_nonzero_level() {
+        let mut engine = engine(100);
+        engine.sync_levels(&[Some(20)], true, ms(0));
+        assert_eq!(engine.backlight(), 20);
+        assert!(!engine.is_fading());
+
+        let mut engine = self::engine(100);
+        engine.sync_levels(&[Some(0)], true, ms(0));
+        assert_eq!(engine.backlight(), 100);
+    }
+
+    #[test]
+    fn reconfigure_keeps_state() {
+        let mut engine = engine(100);
+        engine.handle(Event::Brightness(60), ms(0));
+        run(&mut engine, ms(0), FADE_IN, ms(50));
+        engine.reconfigure(Config { stages: vec![stage(ms(1000), 0)], ..config() }, vec![Channel::default()], ms(500));
+        assert_eq!(engine.backlight(), 60);
+        assert!(!engine.is_fading());
+        // the new timeout counts from the last activity
+        assert_eq!(engine.next_deadline(ms(500)), Some(ms(1000)));
+    }
+
+    #[test]
+    fn reconfigure_rescales_channels() {
+        let mut engine = engine(100);
+        engine.reconfigure(config(), vec![Channel { scale: 0.5, ..Default::default() }], ms(0));
+        let out = run(&mut engine, ms(0), FADE_IN, ms(50));
+        assert_eq!(out.last().unwrap().1, vec![50]);
+    }
+
+    #[test]
+    fn channels_scale_and_keep_minimum() {
+        let channel = Channel { scale: 0.5, min: 10, ..Default::default() };
+        assert_eq!(channel.scaled(100), 50);
+        assert_eq!(channel.scaled(10), 10);
+        assert_eq!(channel.scaled(0), 0);
+        assert_eq!(channel.unscaled(50), 100);
+    }
+
+    #[test]
+    fn channels_use_their_own_curves() {
+        let step = Channel { ease_out: Some(KeyframeFunction::EaseInQuint), ..Default::default() };
+        let mut engine = Engine::new(config(), vec![Channel::default(), step], 100, ms(0));
+        let (_, levels) = run(&mut engine, TIMEOUT, TIMEOUT + FADE_OUT / 2, ms(50)).pop().unwrap();
+        // halfway through, linear is at 50 but ease-in-quint has barely moved
+        assert!(levels[1] > levels[0], "{levels:?}");
+    }
+}
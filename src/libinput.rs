@@ -6,6 +6,7 @@ use tokio::{sync::mpsc, task::JoinHandle};
 use std::time::Instant;
 
 use input::{Libinput, LibinputInterface};
+use input::event::switch::{SwitchEvent, SwitchState};
 use std::fs::{File, OpenOptions};
 use std::os::unix::{fs::OpenOptionsExt, io::OwnedFd};
 use std::path::Path;
@@ -47,6 +48,12 @@ pub enum LibinputSyncEventType {
     Key,
     Gesture,
     Pointer,
+    /// The lid was closed (`true`) or opened (`false`)
+    Lid(bool),
+    /// Folded into (`true`) or out of tablet mode
+    TabletMode(bool),
+    /// Some other switch was toggled
+    Switch,
     Unknown
 }
 
@@ -67,6 +74,11 @@ impl From<&input::Event> for LibinputSyncEvent {
                 Keyboard(_) => t::Key,
                 Gesture(_) => t::Gesture,
                 Pointer(_) => t::Pointer,
+                Switch(SwitchEvent::Toggle(toggle)) => match toggle.switch() {
+                    Some(input::event::switch::Switch::Lid) => t::Lid(toggle.switch_state() == SwitchState::On),
+                    Some(input::event::switch::Switch::TabletMode) => t::TabletMode(toggle.switch_state() == SwitchState::On),
+                    _ => t::Switch,
+                },
                 _ => t::Unknown
             }
         }
@@ -110,11 +122,6 @@ impl LibinputEventListener {
         }
     }
 
-    /// Return `true` if no messages are currently queued up
-    pub fn is_empty(&self) -> bool {
-        self._rx.is_empty()
-    }
-
     /// Wait for the next event and return it
     pub async fn next(&mut self) -> Result<LibinputSyncEvent> {
         self._rx.recv().await.ok_or_else(|| anyhow!("libinput event handler died"))
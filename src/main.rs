@@ -1,87 +1,299 @@
-use std::borrow::Borrow;
-use std::process::Stdio;
-use clap::Parser;
-use framework_lib::chromium_ec::{CrosEc, CrosEcDriverType};
-use libinput::LibinputEventListener;
+use backend::Sink;
+use control::{Command, ControlSocket, Notification, Pending, Reply, Source};
+use dbus::Dbus;
+use engine::{Engine, Event, Input, State, Time};
+use libinput::{LibinputEventListener, LibinputSyncEvent};
 use log::{debug, error, info, trace};
 use uleds::Uleds;
+use std::ffi::OsString;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use keyframe::{ease_with_scaled_time, EasingFunction};
-
-use anyhow::Result;
+use anyhow::{bail, Result};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
 
 mod uleds;
 mod libinput;
 mod cli;
-
-/// Execute `ectool pwmsetkblight <level>`
-async fn ectool_pwmsetkblight(level: u8) -> Result<()> {
-    let cmd = tokio::process::Command::new("ectool")
-        // we specify the interface because otherwise ectool has to figure it out itself
-        // and it takes a while: ~12ms vs ~6ms
-        .args(["--interface=lpc", "--name=cros_ec"])
-        .arg("pwmsetkblight")
-        .arg(level.to_string())
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::inherit())
-        .output().await?;
-    if cmd.status.success() {
-        Ok(())
-    } else {
-        anyhow::bail!("ectool error: {}", String::from_utf8_lossy(&cmd.stderr));
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-enum State {
-    Idle,
-    NotIdle
-}
-
+mod backend;
+mod engine;
+mod config;
+mod watch;
+mod control;
+mod client;
+mod dbus;
+mod als;
+mod power;
+mod sleep;
+#[cfg(test)]
+mod test_util;
+
+/// How often to check if we've been plugged in or unplugged
+const POWER_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Feeds libinput, uleds and the backends into the [`Engine`], and its output back into the backends
 struct Fwkbd {
     _libinput: LibinputEventListener,
-    _ec: Option<CrosEc>,
-    ec_driver: CrosEcDriverType,
-    state: State,
-    /// The current backlight setting, i.e. what we last tried to set it as
-    current_backlight: u8,
-    /// The desired backlight setting, i.e. what the user wants it to be
-    backlight: u8,
-    timeout: Duration,
-    /// Time to fade in the keyboard
-    fade_in: Duration,
-    /// Time to fade out the keyboard
-    fade_out: Duration,
+    /// Everything we're driving, the first one is used to read the backlight back
+    sinks: Vec<Sink>,
+    engine: Engine,
+    /// When the engine's clock started
+    epoch: Instant,
     /// Whether we're listening for uleds changes or not
     uleds: bool,
-    ease_in: cli::KeyframeFunction,
-    ease_out: cli::KeyframeFunction,
+    /// Where to listen for control commands, unless `--no-socket` was given
+    socket: Option<std::path::PathBuf>,
+    socket_mode: u32,
+    socket_group: Option<String>,
+    /// Bus name to serve KbdBacklight as, if `--dbus` was given
+    dbus_name: Option<String>,
     ignore_pointer: bool,
-    tween_spacing: Duration
+    startup: cli::StartupLevel,
+    /// How often to check for the backlight being changed by something else
+    poll_interval: Duration,
+    /// Original command line, to re-read the config with on reload
+    argv: Vec<OsString>,
+    /// Config file to watch for changes, if `--watch-config` was given
+    watch_config: Option<std::path::PathBuf>,
+    /// What to put the keyboard back to, shared with the panic hook
+    restore: Arc<Mutex<Vec<u8>>>,
+    /// Events for control socket subscribers
+    events: broadcast::Sender<Notification>,
+    /// Ambient light sensor, if `--als-threshold` or `--als-curve` was given
+    als: Option<als::AmbientLight>,
+    /// When the room counts as bright
+    als_threshold: Option<als::Threshold>,
+    /// Lux to brightness
+    als_curve: Option<als::Curve>,
+    als_smoothing: als::Smoothing,
+    als_interval: Duration,
+    /// Last ambient light sensor reading, in lux
+    lux: Option<f32>,
+    /// Engine config before the power profile goes on top
+    config: engine::Config,
+    ac_profile: cli::ProfileSpec,
+    battery_profile: cli::ProfileSpec,
+    /// Where sysfs is, if we're watching the power supplies for the profiles
+    power_root: Option<std::path::PathBuf>,
+    /// What we're running on, as of the last check
+    power: Option<power::PowerSource>,
+    /// Cap the backlight below this battery percentage
+    low_battery: Option<u8>,
+    low_battery_max: u8,
+    low_battery_restore: bool,
+    /// Whether the battery was low as of the last check
+    battery_low: bool,
+    /// Why the backlight's capped for the battery, while it is
+    battery_cap: Option<String>,
+    /// Whether to listen for suspend/resume from logind
+    logind: bool,
 }
 
 impl Fwkbd {
-    pub async fn new(args: &cli::Args) -> Result<Self> {
+    pub async fn new(args: &cli::Args, argv: Vec<OsString>) -> Result<Self> {
+        let (sinks, channels) = backend::sinks_from_args(args)?;
+        let epoch = Instant::now();
+        let config = engine::Config::from(args);
+        let engine = Engine::new(config.clone(), channels, args.brightness, Time::ZERO);
+        let als = if args.als_threshold.is_some() || args.als_curve.is_some() {
+            als::AmbientLight::new(&args.sysfs_root).map_err(|e| {
+                error!("error opening ambient light sensor: {e}");
+                e
+            }).ok()
+        } else {
+            None
+        };
         Ok(Fwkbd {
             _libinput: LibinputEventListener::new(),
-            _ec: None,
-            ec_driver: args.driver.as_drivertype().await?,
-            state: State::NotIdle,
-            current_backlight: args.brightness,
-            backlight: args.brightness,
-            timeout: Duration::from_secs_f32(args.timeout),
-            fade_in: Duration::from_secs_f32(args.fade_in),
-            fade_out: Duration::from_secs_f32(args.fade_out),
+            sinks,
+            restore: Arc::new(Mutex::new(engine.restore_levels())),
+            events: broadcast::channel(64).0,
+            engine,
+            epoch,
             uleds: !args.no_uleds,
-            ease_in: args.ease_in,
-            ease_out: args.ease_out,
+            socket: Some(args.socket.clone()).filter(|_| !args.no_socket),
+            socket_mode: args.socket_mode,
+            socket_group: args.socket_group.clone(),
+            dbus_name: Some(args.dbus_name.clone()).filter(|_| args.dbus),
             ignore_pointer: args.ignore_pointer,
-            tween_spacing: Duration::from_millis(50),
+            startup: args.startup,
+            poll_interval: Duration::from_secs_f32(args.poll_interval),
+            argv,
+            // the default file is watched even if it isn't there yet, so creating it counts as a change
+            watch_config: args.watch_config.then(|| args.config.clone().unwrap_or_else(|| config::DEFAULT_PATH.into())),
+            als,
+            als_threshold: args.als_threshold.map(|lux| als::Threshold::new(lux, args.als_hysteresis)),
+            als_curve: args.als_curve.clone(),
+            als_smoothing: als::Smoothing::new(Duration::from_secs_f32(args.als_smoothing)),
+            als_interval: Duration::from_secs_f32(args.als_interval),
+            lux: None,
+            config,
+            ac_profile: args.ac_profile.clone().unwrap_or_default(),
+            battery_profile: args.battery_profile.clone().unwrap_or_default(),
+            power_root: Some(args.sysfs_root.clone()).filter(|_| Self::wants_power(args)),
+            power: None,
+            low_battery: args.low_battery,
+            low_battery_max: args.low_battery_max,
+            low_battery_restore: args.low_battery_restore,
+            battery_low: false,
+            battery_cap: None,
+            logind: !args.no_logind,
         })
     }
 
+    /// Whether we need to keep an eye on the power supplies
+    fn wants_power(args: &cli::Args) -> bool {
+        args.ac_profile.is_some() || args.battery_profile.is_some() || args.low_battery.is_some()
+    }
+
+    /// Re-read the config file and command line, and apply what we can without restarting
+    ///
+    /// Backends, uleds and the startup options are left alone, since changing them needs a restart.
+    fn reload(&mut self) {
+        info!("reloading config");
+        let args = match config::load(&self.argv) {
+            Ok(args) => args,
+            Err(e) => {
+                error!("error reloading config, keeping the old one: {e}");
+                return;
+            },
+        };
+        self.config = engine::Config::from(&args);
+        self.ac_profile = args.ac_profile.clone().unwrap_or_default();
+        self.battery_profile = args.battery_profile.clone().unwrap_or_default();
+        if self.power_root.is_some() != Self::wants_power(&args) {
+            info!("turning power profiles or the low battery cap on or off needs a restart");
+        }
+        self.low_battery = args.low_battery;
+        self.low_battery_max = args.low_battery_max;
+        self.low_battery_restore = args.low_battery_restore;
+        if self.low_battery.is_none() && self.battery_cap.take().is_some() {
+            self.engine.handle(Event::Cap(None), self.now());
+        }
+        let channels = args.sink_specs().iter().map(engine::Channel::from).collect();
+        self.reconfigure(channels);
+        self.ignore_pointer = args.ignore_pointer;
+        self.poll_interval = Duration::from_secs_f32(args.poll_interval);
+        self.als_interval = Duration::from_secs_f32(args.als_interval);
+        if self.als.is_some() != (args.als_threshold.is_some() || args.als_curve.is_some()) {
+            info!("turning the ambient light sensor on or off needs a restart");
+        }
+        let now = self.now();
+        self.als_threshold = match (self.als_threshold.take(), args.als_threshold) {
+            // keep whether it's bright now
+            (Some(mut threshold), Some(lux)) => {
+                threshold.lux = lux;
+                threshold.hysteresis = args.als_hysteresis;
+                Some(threshold)
+            },
+            (_, lux) => {
+                self.engine.handle(Event::RoomBright(false), now);
+                lux.map(|lux| als::Threshold::new(lux, args.als_hysteresis))
+            },
+        };
+        self.als_curve = args.als_curve;
+        if self.als_curve.is_none() {
+            self.engine.handle(Event::Ambient(None), now);
+        }
+        self.als_smoothing.window = Duration::from_secs_f32(args.als_smoothing);
+    }
+
+    /// Give the engine our config with the current power profile on top
+    fn reconfigure(&mut self, channels: Vec<engine::Channel>) {
+        let profile = match self.power {
+            Some(power::PowerSource::Battery) => &self.battery_profile,
+            _ => &self.ac_profile,
+        };
+        let config = self.config.with_profile(profile);
+        self.engine.reconfigure(config, channels, self.now());
+    }
+
+    /// Check if we've been plugged in or unplugged, and switch profiles if so
+    fn check_power(&mut self) {
+        let Some(root) = self.power_root.clone() else { return; };
+        let source = match power::source(&root) {
+            Ok(source) => source,
+            Err(e) => {
+                debug!("error reading power supplies: {e}");
+                return;
+            },
+        };
+        if self.power != Some(source) {
+            info!("running on {source:?} power");
+            self.power = Some(source);
+            self.reconfigure(self.engine.channels().to_vec());
+        }
+        if let Some(threshold) = self.low_battery {
+            match power::battery(&root) {
+                Ok(Some(battery)) => self.check_battery(&battery, threshold),
+                Ok(None) => {},
+                Err(e) => debug!("error reading battery: {e}"),
+            }
+        }
+    }
+
+    /// Cap the backlight while the battery's low, and lift it again once charging if asked to
+    fn check_battery(&mut self, battery: &power::Battery, threshold: u8) {
+        let now = self.now();
+        self.battery_low = battery.is_low(threshold);
+        match power::low_battery(battery, threshold, self.low_battery_restore, self.battery_cap.is_some()) {
+            power::LowBattery::Cap(reason) => {
+                if self.battery_cap.is_none() {
+                    info!("battery is low ({}%), capping the backlight at {}", battery.capacity, self.low_battery_max);
+                }
+                self.battery_cap = Some(reason);
+                self.engine.handle(Event::Cap(Some(self.low_battery_max)), now);
+            },
+            power::LowBattery::Lift => {
+                info!("charging, lifting the low battery cap");
+                self.battery_cap = None;
+                self.engine.handle(Event::Cap(None), now);
+            },
+            power::LowBattery::Unchanged => {},
+        }
+    }
+
+    /// Read the ambient light sensor, and let the engine know if the room got bright or dark
+    fn check_ambient_light(&mut self) {
+        let now = self.now();
+        let Some(ref sensor) = self.als else { return; };
+        let lux = match sensor.lux() {
+            Ok(lux) => lux,
+            Err(e) => {
+                debug!("error reading ambient light sensor: {e}");
+                return;
+            },
+        };
+        trace!("ambient light is {lux} lux");
+        self.lux = Some(lux);
+        if let Some(ref mut threshold) = self.als_threshold {
+            if let Some(bright) = threshold.update(lux) {
+                self.engine.handle(Event::RoomBright(bright), now);
+            }
+        }
+        if let Some(ref curve) = self.als_curve {
+            let smoothed = self.als_smoothing.update(lux, Instant::now());
+            self.engine.handle(Event::Ambient(Some(curve.level(smoothed))), now);
+        }
+    }
+
+    /// Wait for the config file to change, or forever if we aren't watching it
+    async fn config_changed(watcher: &Option<watch::ConfigWatcher>) {
+        match watcher {
+            Some(watcher) => if let Err(e) = watcher.changed().await {
+                error!("error watching config file: {e}");
+                std::future::pending::<()>().await;
+            },
+            None => std::future::pending().await,
+        }
+    }
+
+    /// The engine's idea of the current time
+    fn now(&self) -> Time {
+        self.epoch.elapsed()
+    }
+
     /// returns `true` if uleds is present and could wait
     /// returns `false` immediately if uleds is None
     async fn wait_for_uleds(uleds: &Option<Uleds>) -> bool {
@@ -93,227 +305,489 @@ impl Fwkbd {
         }
     }
 
-    pub async fn set_backlight(&mut self, level: u8) -> Result<()> {
-        trace!("set_backlight({level})");
-        self.get_ec_handle()?;
-        if let Some(ref ec) = self._ec {
-            tokio::task::block_in_place(|| ec.set_keyboard_backlight(level));
-            self.current_backlight = level;
+    /// Sleep until `deadline`, or forever if there isn't one
+    async fn sleep_until(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
         }
-        Ok(())
     }
 
-    fn get_ec_handle(&mut self) -> Result<()> {
-        if self._ec.is_none() {
-            let res = tokio::task::block_in_place(|| {
-                let ec = CrosEc::with(self.ec_driver);
-                match ec {
-                    Some(ec) => Ok(ec),
-                    None => anyhow::bail!("Failed to access EC"),
-                }
-            });
-            self._ec = Some(res?);
+    /// Wait for logind to say we're suspending or resuming, or forever if we aren't listening
+    async fn next_sleep(watcher: &mut Option<sleep::SleepWatcher>) -> Result<bool> {
+        match watcher {
+            Some(watcher) => watcher.next().await,
+            None => std::future::pending().await,
         }
-        Ok(())
     }
 
-    fn flush_ec_handle(&mut self) {
-        self._ec = None;
+    /// Turn the backlight off cleanly before suspending
+    async fn prepare_for_sleep(&mut self) {
+        info!("suspending");
+        let now = self.now();
+        self.engine.handle(Event::Sleep(true), now);
+        if let Some(levels) = self.engine.tick(now) {
+            self.apply(&levels, false).await;
+        }
+        self.release_backend();
     }
 
-    pub async fn async_loop(&mut self) -> Result<()> {
-        use State::*;
-
-        let uleds = if self.uleds {
-            debug!("getting uleds handle");
-            Uleds::new(self.backlight).await.map_err(|e| {
-                error!("error getting uleds handle: {e}");
-                e
-            }).ok()
-        } else {
-            None
-        };
+    /// Re-read where the keyboard is, since the EC may have moved it while we were suspended, and fade back in
+    async fn resume(&mut self) {
+        info!("resumed");
+        // don't trust any handles or levels from before
+        self.release_backend();
+        let mut levels = vec![];
+        for i in 0..self.sinks.len() {
+            let level = self.read_sink(i).await.unwrap_or_else(|e| {
+                debug!("error reading sink {i} after resume: {e}");
+                None
+            });
+            self.sinks[i].current = level;
+            levels.push(level);
+        }
+        let now = self.now();
+        self.engine.sync_levels(&levels, false, now);
+        self.engine.handle(Event::Sleep(false), now);
+    }
 
-        // reset to max backlight
-        self.set_backlight(self.backlight).await?;
+    /// Sleep for `interval`, or forever if polling is disabled
+    async fn poll_tick(interval: Duration) {
+        if interval.is_zero() {
+            std::future::pending::<()>().await;
+        }
+        tokio::time::sleep(interval).await;
+    }
 
-        let timeout = self.timeout;
-    
-        loop {
-            if let Some(ref uleds) = uleds {
-                // get the uled brightness once to prevent race conditions
-                let uleds_brightness = uleds.brightness();
-                if self.backlight != uleds_brightness {
-                    // user changed the led brightness
-                    info!("uleds brightness changed to {uleds_brightness}");
-                    self.state = NotIdle;
-                    self.backlight = uleds_brightness;
-                    self.fade_accordingly().await?;
+    /// Set each sink to its level in `levels`
+    ///
+    /// Errors are just logged, and failing sinks are tried again next time, since any of them can
+    /// go away and come back (e.x. unplugging a Framework 16 input module). If `force` isn't set,
+    /// sinks already at their level are skipped.
+    async fn apply(&mut self, levels: &[u8], force: bool) {
+        trace!("apply({levels:?})");
+        let sinks = &mut self.sinks;
+        #[cfg(debug_assertions)]
+        let i = Instant::now();
+        tokio::task::block_in_place(|| {
+            for (sink, &level) in sinks.iter_mut().zip(levels) {
+                if force || sink.is_failing() || sink.current != Some(level) {
+                    // non-fatal, so this can't fail
+                    let _ = sink.set(level, false);
                 }
             }
+        });
+        #[cfg(debug_assertions)]
+        debug!("setting {levels:?} took {:?}", i.elapsed());
+    }
 
-            match self.state {
-                Idle => {
-                    tokio::select! {
-                        _ = self.get_next_event() => {
-                            self.fade_accordingly().await?;
+    /// Put the keyboard back at the user's brightness, e.x. when we're exiting
+    pub async fn restore_backlight(&mut self) {
+        let levels = self.engine.restore_levels();
+        self.apply(&levels, true).await
+    }
 
-                        }
-                        true = Self::wait_for_uleds(&uleds) => {
-                            //brightness update
-                        }
+    /// Answer a control request
+    async fn handle_command(&mut self, pending: &Pending, uleds: Option<&Uleds>) -> Reply {
+        let now = self.now();
+        match pending.command {
+            Command::Status => return Reply::Status(self.status(now)),
+            Command::SetBrightness { level } => {
+                if level > 100 {
+                    return Reply::error(format!("brightness has to be 0-100, not {level}"));
+                }
+                // same as a uleds change, and keep uleds in line with it
+                self.set_brightness(level, pending.source);
+                if let Some(uleds) = uleds {
+                    if let Err(e) = uleds.set_brightness(level).await {
+                        return Reply::error(format!("error updating uleds: {e}"));
                     }
-                },
-                NotIdle => {
-                    tokio::select! {
-                        _ = self.get_next_event() => {
-                            //idle timer reset
-                        }
-                        true = Self::wait_for_uleds(&uleds) => {
-                            //brightness update
-                        }
-                        _ = tokio::time::sleep(timeout) => {
-                            self.state = Idle;
-                            info!("got sleep");
-                            // this is called twice in case it's interrupted by the user becoming not idle
-                            self.fade_accordingly().await?;
-                            self.fade_accordingly().await?;
-                        }
+                }
+            },
+            Command::SetTiming { timeout, fade_in, fade_out, ease_in, ease_out } => {
+                let mut config = self.config.clone();
+                let Some(first) = config.stages.first_mut() else {
+                    return Reply::error("there are no idle stages to change");
+                };
+                // timeout and fade out are the first idle stage's
+                let seconds = |secs: Option<f32>, current| secs.map_or(Ok(current), cli::duration);
+                let timing = (seconds(timeout, first.timeout), seconds(fade_in, config.fade_in), seconds(fade_out, first.fade_out));
+                let (timeout, fade_in, fade_out) = match timing {
+                    (Ok(timeout), Ok(fade_in), Ok(fade_out)) => (timeout, fade_in, fade_out),
+                    (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return Reply::error(e),
+                };
+                first.timeout = timeout;
+                first.fade_out = fade_out;
+                first.ease_out = ease_out.unwrap_or(first.ease_out);
+                config.fade_in = fade_in;
+                config.ease_in = ease_in.unwrap_or(config.ease_in);
+                config.stages.sort_by_key(|stage| stage.timeout);
+                self.config = config;
+                self.reconfigure(self.engine.channels().to_vec());
+            },
+            Command::Idle if self.engine.is_inhibited() => {
+                let names: Vec<_> = self.engine.inhibitors().iter().map(|i| i.name.as_str()).collect();
+                return Reply::error(format!("inhibited by {}", names.join(", ")));
+            },
+            Command::Idle => self.engine.handle(Event::Idle, now),
+            Command::Wake => {
+                if self.engine.state() == State::Idle {
+                    if let Err(e) = self.sync_external_change(uleds).await {
+                        return Reply::error(e);
                     }
-                },
-            }
-            self.flush_ec_handle();
+                }
+                self.engine.handle(Event::Activity(Input::Other), now);
+            },
+            Command::Inhibit { ref name, ref reason, duration } => {
+                let duration = match duration.map(cli::duration).transpose() {
+                    Ok(duration) => duration,
+                    Err(e) => return Reply::error(format!("invalid duration: {e}")),
+                };
+                if self.engine.state() == State::Idle {
+                    if let Err(e) = self.sync_external_change(uleds).await {
+                        return Reply::error(e);
+                    }
+                }
+                self.engine.inhibit(name, reason.as_deref(), duration, now);
+            },
+            Command::Uninhibit { ref name } => {
+                if !self.engine.uninhibit(name, now) {
+                    return Reply::error(format!("{name} isn't inhibiting"));
+                }
+            },
+            Command::Subscribe => return Reply::error("subscribing only works over the control socket"),
         }
+        Reply::Ok
     }
 
-    /// If the current backlight doesn't match the correct backlight for the current state, fade accordingly
-    /// 
-    /// This uses tokio::task::unconstrained so that the animation is never interrupted by other tasks
-    /// (to ensure a smooth animation)
-    pub async fn fade_accordingly(&mut self) -> Result<()> {
-        trace!("fade_accordingly()");
-        match self.state {
-            State::Idle => if self.current_backlight != 0 {
-                tokio::task::unconstrained(self.fade_backlight(0, self.fade_out, self.ease_out)).await?;
-            },
-            State::NotIdle => if self.current_backlight != self.backlight {
-                tokio::task::unconstrained(self.fade_backlight(self.backlight, self.fade_in, self.ease_in)).await?;
-            },
+    fn status(&self, now: Time) -> control::Status {
+        let config = self.engine.config();
+        control::Status {
+            state: self.engine.state(),
+            target: self.engine.brightness(),
+            backlight: self.engine.backlight(),
+            current: self.engine.current(),
+            levels: self.engine.levels().to_vec(),
+            fading: self.engine.is_fading(),
+            timeout: config.stages.first().map_or(0.0, |stage| stage.timeout.as_secs_f32()),
+            fade_in: config.fade_in.as_secs_f32(),
+            fade_out: config.stages.first().map_or(0.0, |stage| stage.fade_out.as_secs_f32()),
+            stage: self.engine.stage(),
+            stages: config.stages.iter().map(|stage| control::StageStatus {
+                timeout: stage.timeout.as_secs_f32(),
+                level: stage.level,
+                fade_out: stage.fade_out.as_secs_f32(),
+            }).collect(),
+            inhibitors: self.engine.inhibitors().iter().map(|inhibitor| control::InhibitorStatus {
+                name: inhibitor.name.clone(),
+                reason: inhibitor.reason.clone(),
+                count: inhibitor.count,
+                expires_in: inhibitor.expires.map(|expires| expires.saturating_sub(now).as_secs_f32()),
+            }).collect(),
+            lux: self.lux,
+            room_bright: self.engine.is_room_bright(),
+            power: self.power,
+            cap: self.engine.cap(),
+            cap_reason: self.battery_cap.clone(),
+            lid_closed: self.engine.is_lid_closed(),
+            tablet_mode: self.engine.is_tablet_mode(),
         }
-        Ok(())
     }
 
-    /// Wait until the next libinput event comes through.
-    /// 
-    /// Returns `true` if the event made us not idle.
-    async fn get_next_event(&mut self) -> Result<bool> {
-        use State::*;
-        use libinput::LibinputSyncEventType::*;
-        let event = self._libinput.next().await?;
-        if matches!(event.event_type, DeviceAdded | DeviceRemoved) ||
-            (self.ignore_pointer && matches!(event.event_type, Gesture | Pointer)) {
-            return Ok(false);
-        }
-        let changed = self.state == Idle;
-        self.state = NotIdle;
-        Ok(changed)
+    /// The user picked a new brightness
+    fn set_brightness(&mut self, level: u8, source: Source) {
+        // picking a brightness after charging lifts the low battery cap
+        if !self.battery_low && self.battery_cap.take().is_some() {
+            info!("lifting the low battery cap");
+            self.engine.handle(Event::Cap(None), self.now());
+        }
+        self.engine.handle(Event::Brightness(level), self.now());
+        self.publish(Notification::Brightness { level, source });
     }
 
-    /// Get the next libinput event if there's one available.
-    /// 
-    /// Doesn't wait for an event, so if there's no events queued, returns almost immediately.
-    pub async fn try_update(&mut self) -> Result<bool> {
-        use State::*;
-        use libinput::LibinputSyncEventType::*;
-        while !self._libinput.is_empty() {
-            let event = self._libinput.next().await?;
-            if matches!(event.event_type, DeviceAdded | DeviceRemoved) ||
-                (self.ignore_pointer && matches!(event.event_type, Gesture | Pointer)) {
-                continue;
-            }
-            let changed = self.state == Idle;
-            self.state = NotIdle;
-            return Ok(changed);
+    /// Let subscribers know something happened
+    fn publish(&self, notification: Notification) {
+        trace!("publish({notification:?})");
+        // no subscribers isn't an error
+        let _ = self.events.send(notification);
+    }
+
+    /// Keep the panic hook's idea of the user's brightness up to date
+    fn share_restore_levels(&self) {
+        let levels = self.engine.restore_levels();
+        let mut restore = self.restore.lock().expect("restore levels lock poisoned");
+        if *restore != levels {
+            *restore = levels;
+        }
+    }
+
+    /// Print everything we know to stderr, for `kill -USR1`
+    ///
+    /// This skips the logger, so it shows up whatever RUST_LOG is set to (it only shows errors by default).
+    fn dump_state(&self, uleds: Option<&Uleds>) {
+        eprintln!("state dump, uptime {:?}", self.now());
+        eprintln!("{:#?}", self.engine);
+        for (i, sink) in self.sinks.iter().enumerate() {
+            eprintln!("sink {i}: {} current={:?} failing={}", sink.backend.name(), sink.current, sink.is_failing());
         }
-        Ok(false)
+        eprintln!("uleds: {:?}", uleds.map(|u| u.brightness()));
+        eprintln!("ignore_pointer={} poll_interval={:?} watch_config={:?}",
+            self.ignore_pointer, self.poll_interval, self.watch_config);
+        eprintln!("als_threshold={:?} als_curve={:?} als_smoothing={:?} lux={:?}",
+            self.als_threshold, self.als_curve, self.als_smoothing, self.lux);
+        eprintln!("power={:?} ac_profile={:?} battery_profile={:?}", self.power, self.ac_profile, self.battery_profile);
+        eprintln!("low_battery={:?} battery_low={} battery_cap={:?}", self.low_battery, self.battery_low, self.battery_cap);
     }
 
+    /// Check if something else (Fn+Space, ectool, etc) changed the backlight behind our back,
+    /// and if so let the engine know, and keep uleds in line with it
+    async fn sync_external_change(&mut self, uleds: Option<&Uleds>) -> Result<()> {
+        let level = match self.read_sink(0).await {
+            Ok(Some(level)) => level,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                debug!("error reading backlight level: {e}");
+                return Ok(());
+            },
+        };
+        if Some(level) == self.sinks[0].current {
+            return Ok(());
+        }
+        self.sinks[0].current = Some(level);
+        self.engine.handle(Event::External(level), self.now());
+        self.publish(Notification::Brightness { level: self.engine.brightness(), source: Source::External });
+        if let Some(uleds) = uleds {
+            uleds.set_brightness(self.engine.brightness()).await?;
+        }
+        Ok(())
+    }
 
-    /// Fade the keyboard backlight from the current brightness to the goal brightness
-    pub async fn fade_backlight<F: EasingFunction>(&mut self, goal: u8, time: Duration, func: impl Borrow<F> + Copy) -> Result<()> {
-        trace!("fade_backlight(goal={goal})");
+    /// Read the current level back from a sink, if it supports it
+    pub async fn read_sink(&mut self, i: usize) -> Result<Option<u8>> {
+        let sink = &mut self.sinks[i];
+        let level = tokio::task::block_in_place(|| sink.read())?;
+        trace!("read_sink({i}) = {level:?}");
+        Ok(level)
+    }
 
-        if time.is_zero() {
-            return self.set_backlight(goal).await;
+    /// Tell the engine what the keyboard is actually at
+    ///
+    /// Returns `false` if we didn't get a level, and should just reset the backlight.
+    async fn read_startup_level(&mut self) -> bool {
+        if matches!(self.startup, cli::StartupLevel::Reset) {
+            return false;
         }
+        let level = match self.read_sink(0).await {
+            Ok(Some(level)) => level,
+            Ok(None) => {
+                debug!("{} backend can't read the backlight level", self.sinks[0].backend.name());
+                return false;
+            },
+            Err(e) => {
+                error!("error reading backlight level: {e}");
+                return false;
+            },
+        };
+        info!("backlight is currently at {level}");
+        let mut levels = vec![Some(level)];
+        for i in 1..self.sinks.len() {
+            levels.push(self.read_sink(i).await.ok().flatten());
+        }
+        for (sink, &level) in self.sinks.iter_mut().zip(&levels) {
+            sink.current = level;
+        }
+        let adopt = matches!(self.startup, cli::StartupLevel::Adopt);
+        self.engine.sync_levels(&levels, adopt, self.now());
+        true
+    }
 
-        let time_start = Instant::now();
-        let starting_backlight = self.current_backlight as f32;
-        let goal_backlight = goal as f32;
+    /// Let go of the backends' handles so other tools can use them between fades
+    fn release_backend(&mut self) {
+        for sink in &mut self.sinks {
+            sink.backend.release();
+        }
+    }
 
-        let mut elapsed;
-        // instant when we started the animation
-        let mut iteration_start;
+    /// What kind of input `event` was, `None` if it isn't the user doing something
+    fn activity(&self, event: &LibinputSyncEvent) -> Option<Input> {
+        use libinput::LibinputSyncEventType::*;
+        match event.event_type {
+            DeviceAdded | DeviceRemoved | Lid(_) | TabletMode(_) | Switch => None,
+            Gesture | Pointer if self.ignore_pointer => None,
+            Gesture | Pointer => Some(Input::Pointer),
+            Key => Some(Input::Key),
+            Unknown => Some(Input::Other),
+        }
+    }
 
-        // if we're fading down (to idle)
-        // we want to be able to interrupt it and immediately fade back
-        let interruptable = starting_backlight > goal_backlight;
+    pub async fn async_loop(&mut self) -> Result<()> {
+        // this has to happen before uleds is set up, since adopting changes the user's brightness
+        let synced = self.read_startup_level().await;
 
-        #[cfg(debug_assertions)]
-        let mut ectool_avg = vec![];
+        let uleds = if self.uleds {
+            debug!("getting uleds handle");
+            Uleds::new(self.engine.brightness()).await.map_err(|e| {
+                error!("error getting uleds handle: {e}");
+                e
+            }).ok()
+        } else {
+            None
+        };
 
-        loop {
-            iteration_start = Instant::now();
+        // if we synced, this only touches sinks we couldn't read, the engine fades the rest
+        // otherwise, reset to max backlight
+        let levels = self.engine.levels().to_vec();
+        self.apply(&levels, !synced).await;
+        // the main backlight not working from the start is worth giving up over, unlike it going away later
+        if let Some(sink) = self.sinks.first().filter(|sink| sink.is_failing()) {
+            bail!("couldn't set the {} backlight", sink.backend.name());
+        }
 
-            elapsed = time_start.elapsed();
+        let mut requests = control::Requests::new(self.events.clone());
+        let _control = match self.socket {
+            Some(ref path) => ControlSocket::bind(path, self.socket_mode, self.socket_group.as_deref(), requests.requester(Source::Socket)).map_err(|e| {
+                error!("error opening control socket: {e}");
+                e
+            }).ok(),
+            None => None,
+        };
+        let dbus = match self.dbus_name {
+            Some(ref name) => Dbus::new(name, requests.requester(Source::Dbus)).await.map_err(|e| {
+                error!("error connecting to the system bus: {e}");
+                e
+            }).ok(),
+            None => None,
+        };
+        // what we last told D-Bus the brightness was
+        let mut announced = self.engine.brightness();
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut usr1 = signal(SignalKind::user_defined1())?;
+        let watcher = match self.watch_config {
+            Some(ref path) => watch::ConfigWatcher::new(path).map_err(|e| {
+                error!("error watching {}: {e}", path.display());
+                e
+            }).ok(),
+            None => None,
+        };
+        let mut sleep = if self.logind {
+            sleep::SleepWatcher::new().await.map_err(|e| {
+                error!("error connecting to logind, suspend/resume won't be handled: {e}");
+                e
+            }).ok()
+        } else {
+            None
+        };
+
+        // read the ambient light sensor and power supplies straight away
+        let mut next_als = Instant::now();
+        let mut next_power = Instant::now();
 
-            if elapsed >= time {
-                if self.current_backlight != goal {
-                    #[cfg(debug_assertions)]
-                    let i = Instant::now();
-                    self.set_backlight(goal).await?;
-                    #[cfg(debug_assertions)]
-                    ectool_avg.push(i.elapsed());
+        loop {
+            if let Some(ref uleds) = uleds {
+                // get the uled brightness once to prevent race conditions
+                let uleds_brightness = uleds.brightness();
+                if self.engine.brightness() != uleds_brightness {
+                    // user changed the led brightness
+                    self.set_brightness(uleds_brightness, Source::Uleds);
+                }
+            }
+            if let Some(ref dbus) = dbus {
+                if self.engine.brightness() != announced {
+                    announced = self.engine.brightness();
+                    if let Err(e) = dbus.brightness_changed(announced).await {
+                        debug!("error sending BrightnessChanged: {e}");
+                    }
                 }
-                break;
             }
 
-            let tween = ease_with_scaled_time(func, starting_backlight, goal_backlight, elapsed.as_secs_f32(), time.as_secs_f32()) as u8;
-            if tween == self.current_backlight {
-                debug!("tweened too fast");
-                tokio::time::sleep(self.tween_spacing).await;
-                continue;
+            let now = self.now();
+            if let Some(levels) = self.engine.tick(now) {
+                self.apply(&levels, false).await;
             }
-            #[cfg(debug_assertions)]
-            let i = Instant::now();
-            self.set_backlight(tween).await?;
-            #[cfg(debug_assertions)]
-            ectool_avg.push(i.elapsed());
-            debug!("tween={tween}, elapsed={elapsed:?}");
-
-            if tween == goal {
-                break;
+            if !self.engine.is_fading() {
+                self.release_backend();
             }
-
-            // if we're dimming down, we should be checking for being no longer idle
-            if interruptable {
-                // try and update
-                if self.try_update().await? {
-                    // user isn't idle anymore, break out of this fade
-                    break;
+            self.share_restore_levels();
+            for notice in self.engine.take_notices() {
+                self.publish(notice.into());
+            }
+            let deadline = self.engine.next_deadline(now).and_then(|t| self.epoch.checked_add(t));
+            let fading = self.engine.is_fading();
+            let poll_interval = self.poll_interval;
+
+            tokio::select! {
+                event = self._libinput.next() => {
+                    let event = event?;
+                    let when = event.instant.saturating_duration_since(self.epoch);
+                    if let libinput::LibinputSyncEventType::Lid(closed) = event.event_type {
+                        if !closed && self.engine.state() == State::Idle {
+                            self.sync_external_change(uleds.as_ref()).await?;
+                        }
+                        self.engine.handle(Event::Lid(closed), when);
+                    } else if let libinput::LibinputSyncEventType::TabletMode(folded) = event.event_type {
+                        if !folded && self.engine.state() == State::Idle {
+                            self.sync_external_change(uleds.as_ref()).await?;
+                        }
+                        self.engine.handle(Event::TabletMode(folded), when);
+                    } else if let Some(input) = self.activity(&event) {
+                        if self.engine.state() == State::Idle && self.engine.accepts(input) {
+                            // make sure we fade in from where the keyboard actually is
+                            self.sync_external_change(uleds.as_ref()).await?;
+                        }
+                        self.engine.handle(Event::Activity(input), when);
+                    }
+                }
+                true = Self::wait_for_uleds(&uleds) => {
+                    //brightness update
+                }
+                _ = Self::poll_tick(poll_interval), if !fading => {
+                    self.sync_external_change(uleds.as_ref()).await?;
+                }
+                _ = Self::sleep_until(deadline) => {
+                    // idle timeout or next step of a fade
+                }
+                _ = hangup.recv() => {
+                    self.reload();
+                }
+                _ = Self::config_changed(&watcher) => {
+                    self.reload();
+                }
+                pending = requests.next() => {
+                    let reply = self.handle_command(&pending, uleds.as_ref()).await;
+                    pending.reply(reply);
+                }
+                _ = usr1.recv() => {
+                    self.dump_state(uleds.as_ref());
+                }
+                _ = tokio::time::sleep_until(next_als.into()), if self.als.is_some() => {
+                    self.check_ambient_light();
+                    next_als = Instant::now() + self.als_interval;
+                }
+                start = Self::next_sleep(&mut sleep) => match start {
+                    Ok(true) => {
+                        self.prepare_for_sleep().await;
+                        if let Some(ref mut watcher) = sleep {
+                            watcher.release();
+                        }
+                    },
+                    Ok(false) => {
+                        self.resume().await;
+                        if let Some(ref mut watcher) = sleep {
+                            watcher.lock().await;
+                        }
+                    },
+                    Err(e) => {
+                        error!("error watching for suspend/resume: {e}");
+                        sleep = None;
+                    },
+                },
+                _ = tokio::time::sleep_until(next_power.into()), if self.power_root.is_some() => {
+                    self.check_power();
+                    next_power = Instant::now() + POWER_POLL_INTERVAL;
                 }
             }
-
-            // sleep so we don't make a million ectool calls
-            let Some(sleep_timer) = self.tween_spacing.checked_sub(iteration_start.elapsed()) else { continue; };
-            tokio::time::sleep(sleep_timer).await;
         }
-        #[cfg(debug_assertions)]
-        debug!("ectool ran {} times and averaged {:?}",
-            ectool_avg.len(),
-            ectool_avg.iter().sum::<Duration>().checked_div(ectool_avg.len() as u32).unwrap()
-        );
-        Ok(())
     }
 }
 
@@ -321,23 +795,53 @@ impl Fwkbd {
 //#[tokio::main(flavor = "current_thread")]
 #[tokio::main(worker_threads = 2)]
 async fn main() -> Result<()> {
-    let args = cli::Args::parse();
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let args = config::load(&argv)?;
 
     env_logger::init();
 
+    if let Some(ref command) = args.command {
+        return client::run(&args, command).await;
+    }
+
     // start the program
-    let mut fwkbd = Fwkbd::new(&args).await?;
+    let mut fwkbd = Fwkbd::new(&args, argv).await?;
+    restore_on_panic(args, fwkbd.restore.clone());
 
-    tokio::select! {
-        e = fwkbd.async_loop() => {
-            e?;
-        }
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let res = tokio::select! {
+        res = fwkbd.async_loop() => res,
         _ = tokio::signal::ctrl_c() => {
+            error!("got SIGINT, resetting backlight and closing");
+            Ok(())
+        }
+        _ = sigterm.recv() => {
             error!("got SIGTERM, resetting backlight and closing");
-            let _ = fwkbd.set_backlight(fwkbd.backlight).await;
-            std::process::exit(0);
+            Ok(())
         }
-    }
+    };
+    fwkbd.restore_backlight().await;
+    res?;
+    std::process::exit(0);
+}
 
-    Ok(())
+/// Put the keyboard back at the user's brightness if we panic
+///
+/// Release builds use `panic = "abort"`, so nothing gets unwound and `Fwkbd` never gets a chance
+/// to clean up. Instead this builds a fresh set of backends from the hook and sets them directly.
+fn restore_on_panic(mut args: cli::Args, restore: Arc<Mutex<Vec<u8>>>) {
+    // don't truncate the recording
+    args.record = None;
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let Ok(levels) = restore.try_lock().map(|levels| levels.clone()) else { return; };
+        error!("panicked, resetting backlight to {levels:?}");
+        match backend::sinks_from_args(&args) {
+            Ok((mut sinks, _)) => for (sink, level) in sinks.iter_mut().zip(levels) {
+                let _ = sink.set(level, false);
+            },
+            Err(e) => error!("error resetting backlight: {e}"),
+        }
+    }));
 }
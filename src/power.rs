@@ -0,0 +1,211 @@
+//! AC/battery detection, through power_supply sysfs
+//!
+//! https://www.kernel.org/doc/html/latest/power/power_supply_class.html
+use anyhow::{Context, Result};
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::Path;
+
+/// Relative to the sysfs root
+const POWER_SUPPLY_DIR: &str = "class/power_supply";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+/// Whether we're plugged in, going by the AC adapters (and USB-C chargers) under `sysfs_root`
+///
+/// Machines without any adapters at all, e.x. desktops, count as being on AC.
+pub fn source(sysfs_root: &Path) -> Result<PowerSource> {
+    let dir = sysfs_root.join(POWER_SUPPLY_DIR);
+    let mut adapters = 0;
+    for entry in fs::read_dir(&dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+        if !matches!(kind.trim(), "Mains" | "USB") {
+            continue;
+        }
+        adapters += 1;
+        let online = fs::read_to_string(path.join("online")).unwrap_or_default();
+        trace!("{}: type={} online={}", path.display(), kind.trim(), online.trim());
+        if online.trim() == "1" {
+            return Ok(PowerSource::Ac);
+        }
+    }
+    Ok(if adapters == 0 { PowerSource::Ac } else { PowerSource::Battery })
+}
+
+#[derive(Clone, Debug)]
+pub struct Battery {
+    /// Percent
+    pub capacity: u8,
+    /// e.x. Charging, Discharging, Full, Not charging
+    pub status: String,
+}
+
+impl Battery {
+    pub fn is_discharging(&self) -> bool {
+        self.status == "Discharging"
+    }
+
+    /// Running on it, and under `threshold` percent
+    pub fn is_low(&self, threshold: u8) -> bool {
+        self.is_discharging() && self.capacity < threshold
+    }
+}
+
+/// What to do with the low battery cap after a reading
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum LowBattery {
+    /// Cap the backlight, for this reason
+    Cap(String),
+    Lift,
+    Unchanged,
+}
+
+/// Decide on the low battery cap, given whether it's `capped` right now
+///
+/// Once charging, the cap is lifted if `restore` is set, otherwise it stays until the brightness is changed.
+pub fn low_battery(battery: &Battery, threshold: u8, restore: bool, capped: bool) -> LowBattery {
+    if battery.is_low(threshold) {
+        LowBattery::Cap(format!("battery at {}%, under {threshold}%", battery.capacity))
+    } else if capped && !battery.is_discharging() {
+        if restore {
+            LowBattery::Lift
+        } else {
+            LowBattery::Cap("battery was low, until the brightness is changed".to_string())
+        }
+    } else {
+        LowBattery::Unchanged
+    }
+}
+
+/// The first battery under `sysfs_root`, if there is one
+pub fn battery(sysfs_root: &Path) -> Result<Option<Battery>> {
+    let dir = sysfs_root.join(POWER_SUPPLY_DIR);
+    let mut paths = vec![];
+    for entry in fs::read_dir(&dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        paths.push(entry?.path());
+    }
+    paths.sort();
+    for path in paths {
+        let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+        // e.x. a wireless mouse's battery doesn't count
+        let scope = fs::read_to_string(path.join("scope")).unwrap_or_default();
+        if kind.trim() != "Battery" || scope.trim() == "Device" {
+            continue;
+        }
+        let capacity = fs::read_to_string(path.join("capacity"))
+            .with_context(|| format!("failed to read {}/capacity", path.display()))?;
+        let capacity = capacity.trim().parse()
+            .with_context(|| format!("bad capacity in {}: {}", path.display(), capacity.trim()))?;
+        let status = fs::read_to_string(path.join("status")).unwrap_or_default().trim().to_string();
+        trace!("{}: capacity={capacity} status={status}", path.display());
+        return Ok(Some(Battery { capacity, status }));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn supply(root: &TempDir, name: &str, kind: &str, online: &str) {
+        root.write(&format!("class/power_supply/{name}/type"), &format!("{kind}\n"));
+        root.write(&format!("class/power_supply/{name}/online"), &format!("{online}\n"));
+    }
+
+    fn with_battery() -> TempDir {
+        let root = TempDir::new();
+        root.write("class/power_supply/BAT1/type", "Battery\n");
+        root.write("class/power_supply/BAT1/capacity", "80\n");
+        root
+    }
+
+    #[test]
+    fn mains_online() {
+        let root = with_battery();
+        supply(&root, "ACAD", "Mains", "1");
+        assert_eq!(source(root.path()).unwrap(), PowerSource::Ac);
+    }
+
+    #[test]
+    fn usb_c_online() {
+        let root = with_battery();
+        supply(&root, "ACAD", "Mains", "0");
+        supply(&root, "ucsi-source-psy-USBC000:001", "USB", "0");
+        supply(&root, "ucsi-source-psy-USBC000:002", "USB", "1");
+        assert_eq!(source(root.path()).unwrap(), PowerSource::Ac);
+    }
+
+    #[test]
+    fn no_adapters_is_ac() {
+        let root = TempDir::new();
+        root.write("class/power_supply/hidpp_battery_0/type", "Battery\n");
+        assert_eq!(source(root.path()).unwrap(), PowerSource::Ac);
+    }
+
+    #[test]
+    fn adapters_offline() {
+        let root = with_battery();
+        supply(&root, "ACAD", "Mains", "0");
+        supply(&root, "ucsi-source-psy-USBC000:001", "USB", "0");
+        assert_eq!(source(root.path()).unwrap(), PowerSource::Battery);
+    }
+
+    #[test]
+    fn battery_skips_device_batteries() {
+        let root = TempDir::new();
+        // a wireless mouse, which sorts first
+        root.write("class/power_supply/BAT-mouse/type", "Battery\n");
+        root.write("class/power_supply/BAT-mouse/scope", "Device\n");
+        root.write("class/power_supply/BAT-mouse/capacity", "5\n");
+        root.write("class/power_supply/BAT1/type", "Battery\n");
+        root.write("class/power_supply/BAT1/capacity", "42\n");
+        root.write("class/power_supply/BAT1/status", "Discharging\n");
+        supply(&root, "ACAD", "Mains", "0");
+        let battery = battery(root.path()).unwrap().unwrap();
+        assert_eq!(battery.capacity, 42);
+        assert!(battery.is_discharging());
+    }
+
+    #[test]
+    fn no_battery() {
+        let root = TempDir::new();
+        supply(&root, "ACAD", "Mains", "1");
+        assert!(battery(root.path()).unwrap().is_none());
+    }
+
+    fn reading(capacity: u8, status: &str) -> Battery {
+        Battery { capacity, status: status.to_string() }
+    }
+
+    #[test]
+    fn caps_on_low_battery() {
+        assert_eq!(low_battery(&reading(15, "Discharging"), 20, false, false),
+            LowBattery::Cap("battery at 15%, under 20%".to_string()));
+        assert_eq!(low_battery(&reading(25, "Discharging"), 20, false, false), LowBattery::Unchanged);
+        // plugged in, it isn't low however empty it is
+        assert_eq!(low_battery(&reading(5, "Charging"), 20, false, false), LowBattery::Unchanged);
+    }
+
+    #[test]
+    fn charging_lifts_cap_with_restore() {
+        assert_eq!(low_battery(&reading(15, "Charging"), 20, true, true), LowBattery::Lift);
+        assert_eq!(low_battery(&reading(15, "Not charging"), 20, true, true), LowBattery::Lift);
+    }
+
+    #[test]
+    fn charging_keeps_cap_without_restore() {
+        assert_eq!(low_battery(&reading(15, "Charging"), 20, false, true),
+            LowBattery::Cap("battery was low, until the brightness is changed".to_string()));
+        // the user changed the brightness since, so it stays lifted
+        assert_eq!(low_battery(&reading(15, "Charging"), 20, false, false), LowBattery::Unchanged);
+    }
+}
@@ -0,0 +1,149 @@
+//! Suspend/resume awareness, through logind's PrepareForSleep signal
+//!
+//! We hold a delay inhibitor so logind waits for us to turn the backlight off before suspending,
+//! and take it again after resuming.
+//!
+//! https://www.freedesktop.org/wiki/Software/systemd/inhibit/
+use anyhow::Result;
+use futures_lite::StreamExt;
+use log::{debug, info};
+use zbus::{proxy, zvariant::OwnedFd, Connection};
+
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Manager {
+    fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::Result<OwnedFd>;
+
+    #[zbus(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+}
+
+/// Tells us when logind is about to suspend or has resumed, and holds the delay lock in between
+///
+/// The lock is taken up front and after every resume, and has to be released (once the backlight's
+/// off) for a suspend to go ahead before logind's `InhibitDelayMaxSec` runs out.
+pub struct SleepWatcher {
+    manager: ManagerProxy<'static>,
+    signals: PrepareForSleepStream,
+    /// Held until we're ready to go to sleep
+    lock: Option<OwnedFd>,
+}
+
+impl SleepWatcher {
+    /// Ask logind on the system bus, and take a delay lock
+    pub async fn new() -> Result<Self> {
+        Self::watch(&Connection::system().await?).await
+    }
+
+    /// [`SleepWatcher::new`], talking to whatever owns logind's name on `connection`
+    async fn watch(connection: &Connection) -> Result<Self> {
+        let manager = ManagerProxy::new(connection).await?;
+        let signals = manager.receive_prepare_for_sleep().await?;
+        let mut watcher = SleepWatcher { manager, signals, lock: None };
+        watcher.lock().await;
+        info!("watching for suspend/resume");
+        Ok(watcher)
+    }
+
+    /// Wait for logind to say we're about to suspend (`true`) or just resumed (`false`)
+    pub async fn next(&mut self) -> Result<bool> {
+        let signal = self.signals.next().await
+            .ok_or_else(|| anyhow::anyhow!("PrepareForSleep stream ended"))?;
+        Ok(signal.args()?.start)
+    }
+
+    /// Take a delay lock, so we get a chance to turn the backlight off before suspending
+    ///
+    /// Without one we still hear about suspends, just without logind waiting for us.
+    pub async fn lock(&mut self) {
+        if self.lock.is_some() {
+            return;
+        }
+        match self.manager.inhibit("sleep", env!("CARGO_PKG_NAME"), "Turning the keyboard backlight off", "delay").await {
+            Ok(fd) => self.lock = Some(fd),
+            Err(e) => debug!("couldn't take a sleep delay lock: {e}"),
+        }
+    }
+
+    /// Let logind get on with suspending
+    pub fn release(&mut self) {
+        self.lock = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestBus;
+    use std::io::{ErrorKind, Read};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use zbus::{connection, interface, object_server::SignalEmitter};
+
+    const PATH: &str = "/org/freedesktop/login1";
+
+    /// Hands out delay locks, keeping the other end of each to see when they're let go
+    struct FakeLogind {
+        locks: Arc<Mutex<Vec<UnixStream>>>,
+    }
+
+    #[interface(name = "org.freedesktop.login1.Manager")]
+    impl FakeLogind {
+        fn inhibit(&self, what: &str, _who: &str, _why: &str, mode: &str) -> zbus::fdo::Result<OwnedFd> {
+            assert_eq!((what, mode), ("sleep", "delay"));
+            let (ours, theirs) = UnixStream::pair().map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?;
+            ours.set_nonblocking(true).unwrap();
+            self.locks.lock().unwrap().push(ours);
+            Ok(std::os::fd::OwnedFd::from(theirs).into())
+        }
+
+        #[zbus(signal)]
+        async fn prepare_for_sleep(emitter: &SignalEmitter<'_>, start: bool) -> zbus::Result<()>;
+    }
+
+    /// Whether the `n`th lock handed out is still held, waiting a bit for it to be let go
+    async fn held(locks: &Mutex<Vec<UnixStream>>, n: usize) -> bool {
+        for _ in 0..50 {
+            match locks.lock().unwrap()[n].read(&mut [0]) {
+                Ok(0) => return false,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                other => panic!("unexpected read from lock {n}: {other:?}"),
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        true
+    }
+
+    #[tokio::test]
+    async fn releases_lock_for_suspend_and_retakes_it() {
+        let Some(bus) = TestBus::start() else { return; };
+        let locks = Arc::new(Mutex::new(vec![]));
+        let logind = connection::Builder::address(bus.address.as_str()).unwrap()
+            .name("org.freedesktop.login1").unwrap()
+            .serve_at(PATH, FakeLogind { locks: locks.clone() }).unwrap()
+            .build().await.unwrap();
+        let emitter = SignalEmitter::new(&logind, PATH).unwrap();
+
+        let connection = connection::Builder::address(bus.address.as_str()).unwrap().build().await.unwrap();
+        let mut watcher = SleepWatcher::watch(&connection).await.unwrap();
+        assert_eq!(locks.lock().unwrap().len(), 1);
+        assert!(held(&locks, 0).await);
+
+        FakeLogind::prepare_for_sleep(&emitter, true).await.unwrap();
+        assert!(watcher.next().await.unwrap());
+        watcher.release();
+        assert!(!held(&locks, 0).await);
+
+        FakeLogind::prepare_for_sleep(&emitter, false).await.unwrap();
+        assert!(!watcher.next().await.unwrap());
+        watcher.lock().await;
+        // and only once
+        watcher.lock().await;
+        assert_eq!(locks.lock().unwrap().len(), 2);
+        assert!(held(&locks, 1).await);
+    }
+}
@@ -0,0 +1,92 @@
+//! Helpers for tests that need files, e.x. a fake sysfs tree, or a bus to talk to
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// A scratch directory, removed when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicU32 = AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "fwkbd-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).expect("failed to create temp dir");
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write `contents` to `rel`, creating any directories on the way
+    pub fn write(&self, rel: &str, contents: &str) -> PathBuf {
+        let path = self.path.join(rel);
+        fs::create_dir_all(path.parent().expect("no parent dir")).expect("failed to create dirs");
+        fs::write(&path, contents).expect("failed to write file");
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A private dbus-daemon, for testing D-Bus code without touching the real system bus
+pub struct TestBus {
+    pub address: String,
+    daemon: Child,
+    _dir: TempDir,
+}
+
+impl TestBus {
+    /// Start one, or `None` (after saying so) if dbus-daemon isn't installed, so the test can skip itself
+    pub fn start() -> Option<Self> {
+        let dir = TempDir::new();
+        let config = dir.write("bus.conf", &format!(r#"<busconfig>
+  <listen>unix:path={}/bus</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow user="*"/>
+    <allow own="*"/>
+    <allow send_destination="*"/>
+    <allow receive_sender="*"/>
+  </policy>
+</busconfig>
+"#, dir.path().display()));
+        let mut daemon = match Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                eprintln!("skipping, couldn't start dbus-daemon: {e}");
+                return None;
+            },
+        };
+        // it prints the address once it's listening
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().expect("no stdout")).read_line(&mut address)
+            .expect("failed to read the bus address");
+        Some(TestBus { address: address.trim().to_string(), daemon, _dir: dir })
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
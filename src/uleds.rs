@@ -16,8 +16,12 @@ use std::sync::atomic::{AtomicU8, Ordering};
 
 const LED_MAX_NAME_SIZE: usize = 64;
 
-const DEVICE_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "::kbd_backlight");
-//const DEVICE_NAME: &str = concat!("tpacpi::kbd_backlight");
+pub const DEVICE_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "::kbd_backlight");
+//pub const DEVICE_NAME: &str = concat!("tpacpi::kbd_backlight");
+
+fn brightness_path() -> String {
+    format!("/sys/class/leds/{DEVICE_NAME}/brightness")
+}
 
 pub struct Uleds {
     _handle: JoinHandle<()>,
@@ -46,8 +50,7 @@ impl Uleds {
         file.flush().await?;
 
         // force our brightness to start at 100
-        tokio::fs::write(format!("/sys/class/leds/{DEVICE_NAME}/brightness"),
-            starting_brightness.to_string()).await?;
+        tokio::fs::write(brightness_path(), starting_brightness.to_string()).await?;
 
         // set up the reading buffer
         let brightness = Arc::new(AtomicU8::new(starting_brightness));
@@ -85,4 +88,11 @@ impl Uleds {
     pub fn brightness(&self) -> u8 {
         self._brightness.load(Ordering::Relaxed)
     }
+
+    /// Change the brightness from our end, e.x. when the backlight was changed by something else
+    pub async fn set_brightness(&self, level: u8) -> Result<()> {
+        self._brightness.store(level, Ordering::Relaxed);
+        tokio::fs::write(brightness_path(), level.to_string()).await?;
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,113 @@
+//! inotify watch on the config file
+//!
+//! Watches the file's directory rather than the file itself, so editors that save by writing a
+//! new file and renaming it over the old one still get noticed.
+use anyhow::{anyhow, Result};
+use rustix::fs::inotify::{self, CreateFlags, WatchFlags};
+use tokio::io::unix::AsyncFd;
+
+use std::ffi::OsString;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Size of `struct inotify_event`, not counting the name
+const EVENT_HEADER_LEN: usize = 16;
+
+pub struct ConfigWatcher {
+    fd: AsyncFd<OwnedFd>,
+    name: OsString,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Result<Self> {
+        let name = path.file_name()
+            .ok_or_else(|| anyhow!("{} isn't a file", path.display()))?
+            .to_os_string();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let fd = inotify::inotify_init(CreateFlags::CLOEXEC | CreateFlags::NONBLOCK)?;
+        inotify::inotify_add_watch(&fd, dir, WatchFlags::CLOSE_WRITE | WatchFlags::MOVED_TO | WatchFlags::CREATE)?;
+        Ok(ConfigWatcher {
+            fd: AsyncFd::new(fd)?,
+            name,
+        })
+    }
+
+    /// Wait until the config file is written or replaced
+    pub async fn changed(&self) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let len = match guard.try_io(|fd| Ok(rustix::io::read(fd.get_ref(), &mut buf)?)) {
+                Ok(res) => res?,
+                Err(_would_block) => continue,
+            };
+            if event_names(&buf[..len]).any(|name| name == self.name.as_bytes()) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Names from a buffer of `struct inotify_event`s
+fn event_names(mut buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        if buf.len() < EVENT_HEADER_LEN {
+            return None;
+        }
+        let name_len = u32::from_ne_bytes(buf[12..16].try_into().unwrap()) as usize;
+        let end = (EVENT_HEADER_LEN + name_len).min(buf.len());
+        let name = &buf[EVENT_HEADER_LEN..end];
+        buf = &buf[end..];
+        // names are nul padded
+        Some(name.split(|&b| b == 0).next().unwrap_or_default())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `struct inotify_event` for `name`, padded out like the kernel does
+    fn event(name: &str, padded_len: usize) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend(1i32.to_ne_bytes()); // wd
+        buf.extend(0x8u32.to_ne_bytes()); // mask, IN_CLOSE_WRITE
+        buf.extend(0u32.to_ne_bytes()); // cookie
+        buf.extend((padded_len as u32).to_ne_bytes());
+        let mut name = name.as_bytes().to_vec();
+        name.resize(padded_len, 0);
+        buf.extend(name);
+        buf
+    }
+
+    #[test]
+    fn reads_padded_names() {
+        let mut buf = event("fwkbd.toml", 16);
+        buf.extend(event(".fwkbd.toml.swp", 16));
+        // exactly a multiple of the padding still gets a nul
+        buf.extend(event("0123456789abcdef", 32));
+        // the watched directory itself has no name
+        buf.extend(event("", 0));
+        buf.extend(event("fwkbd.toml~", 16));
+        let names: Vec<&[u8]> = event_names(&buf).collect();
+        assert_eq!(names, vec![
+            &b"fwkbd.toml"[..],
+            &b".fwkbd.toml.swp"[..],
+            &b"0123456789abcdef"[..],
+            &b""[..],
+            &b"fwkbd.toml~"[..],
+        ]);
+    }
+
+    #[test]
+    fn stops_at_truncated_events() {
+        let mut buf = event("fwkbd.toml", 16);
+        buf.extend(&event("other", 16)[..10]);
+        let names: Vec<&[u8]> = event_names(&buf).collect();
+        assert_eq!(names, vec![&b"fwkbd.toml"[..]]);
+    }
+}